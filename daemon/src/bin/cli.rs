@@ -0,0 +1,1526 @@
+use clap::{AppSettings, Parser, Subcommand};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error as _;
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::{fmt, process};
+use system76_firmware::changelog::{Changelog, Version};
+use system76_firmware::*;
+use system76_firmware_daemon::{self as daemon, Client, Digest};
+
+#[derive(Parser)]
+#[clap(
+    name = "system76-firmware-cli",
+    about = "Download and install updates of System76 firmware",
+    long_about = "Download and install updates of System76 firmware.\n\n\
+                  Exit codes:\n\
+                  0   success, or with check, the firmware is up to date\n\
+                  1   any failure without a more specific code\n\
+                  2   with check, newer firmware is available\n\
+                  3   the model is not supported\n\
+                  4   firmware could not be downloaded\n\
+                  5   downloaded firmware did not match its signature or digest\n\
+                  6   the BIOS, EC, or ME could not be read\n\
+                  7   the EFI boot variables could not be changed\n\
+                  8   the updater could not be written to or removed from the ESP\n\
+                  9   a Thelio Io device could not be found or flashed\n\
+                  10  with check, the installed version is not in the changelog\n\
+                  11  the system was not booted with UEFI",
+    setting = AppSettings::SubcommandRequired
+)]
+struct Args {
+    #[clap(
+        help = "Print the result as a JSON document on stdout",
+        long = "json",
+        global = true
+    )]
+    json: bool,
+    #[clap(
+        help = "Accept firmware manifests older than the newest one seen before",
+        long = "allow-downgrade",
+        global = true
+    )]
+    allow_downgrade: bool,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(clap::Args)]
+struct Transition {
+    #[clap(help = "Select open firmware", long = "open")]
+    open: bool,
+    #[clap(
+        help = "Select proprietary firmware",
+        long = "proprietary",
+        conflicts_with = "open"
+    )]
+    proprietary: bool,
+}
+
+impl Transition {
+    fn kind(&self) -> TransitionKind {
+        if self.open {
+            TransitionKind::Open
+        } else if self.proprietary {
+            TransitionKind::Proprietary
+        } else {
+            TransitionKind::Automatic
+        }
+    }
+}
+
+/// Exit code of `check` when the installed firmware is the newest available.
+const EXIT_UP_TO_DATE: i32 = 0;
+/// Exit code for failures that have no more specific code.
+const EXIT_FAILURE: i32 = 1;
+/// Exit code of `check` when newer firmware is available.
+const EXIT_UPDATE_AVAILABLE: i32 = 2;
+/// Exit code when the model is not supported.
+const EXIT_UNSUPPORTED: i32 = 3;
+/// Exit code when firmware could not be downloaded.
+const EXIT_DOWNLOAD_FAILED: i32 = 4;
+/// Exit code when downloaded firmware did not match its signature or digest.
+const EXIT_VERIFICATION_FAILED: i32 = 5;
+/// Exit code when the BIOS, EC, or ME could not be read.
+const EXIT_HARDWARE: i32 = 6;
+/// Exit code when the EFI boot variables could not be changed.
+const EXIT_EFI_VARS: i32 = 7;
+/// Exit code when the updater could not be written to or removed from the ESP.
+const EXIT_ESP: i32 = 8;
+/// Exit code when a Thelio Io device could not be found or flashed.
+const EXIT_THELIO_IO: i32 = 9;
+/// Exit code of `check` when the installed BIOS is not in the changelog.
+const EXIT_NOT_LISTED: i32 = 10;
+/// Exit code when an update is scheduled on a system not booted with UEFI.
+const EXIT_NOT_UEFI: i32 = 11;
+
+#[derive(Subcommand)]
+enum Command {
+    #[clap(
+        about = "Check whether newer firmware is available",
+        long_about = "Check whether newer firmware is available.\n\n\
+                      Exits with 0 if up to date, 2 if an update is available, and \
+                      10 if the installed version is not in the changelog. The other \
+                      exit codes are listed in the main help."
+    )]
+    Check {
+        #[clap(flatten)]
+        transition: Transition,
+    },
+    #[clap(about = "Download firmware into the cache without scheduling it")]
+    Download {
+        #[clap(flatten)]
+        transition: Transition,
+    },
+    #[clap(about = "Show release notes of the available firmware")]
+    Changelog {
+        #[clap(flatten)]
+        transition: Transition,
+    },
+    #[clap(about = "Schedule installation of firmware for next boot")]
+    Schedule {
+        #[clap(flatten)]
+        transition: Transition,
+        #[clap(
+            help = "Install from an offline bundle instead of downloading",
+            long = "bundle",
+            value_name = "FILE"
+        )]
+        bundle: Option<PathBuf>,
+        #[clap(
+            help = "Show the changes to the ESP and EFI variables without making them",
+            long = "dry-run"
+        )]
+        dry_run: bool,
+    },
+    #[clap(about = "Cancel scheduled firmware installation")]
+    Unschedule {
+        #[clap(
+            help = "Show the changes to the ESP and EFI variables without making them",
+            long = "dry-run"
+        )]
+        dry_run: bool,
+    },
+    #[clap(about = "List the firmware published for all models")]
+    List {
+        #[clap(help = "Only list firmware for this model", long = "model")]
+        model: Option<String>,
+        #[clap(
+            help = "Download each image to show the newest version in its changelog",
+            long = "changelog"
+        )]
+        changelog: bool,
+    },
+    #[clap(about = "Manage the download cache")]
+    Cache {
+        #[clap(subcommand)]
+        command: CacheCommand,
+    },
+    #[clap(about = "Export firmware to a bundle for offline installation")]
+    Export {
+        #[clap(
+            help = "Firmware ID to export",
+            long = "firmware-id",
+            required_unless_present = "model",
+            conflicts_with = "model"
+        )]
+        firmware_id: Option<String>,
+        #[clap(
+            help = "Model to export firmware for",
+            long = "model",
+            requires = "ec-project"
+        )]
+        model: Option<String>,
+        #[clap(
+            help = "EC project of the model to export firmware for",
+            long = "ec-project",
+            requires = "model"
+        )]
+        ec_project: Option<String>,
+        #[clap(help = "Bundle file to write")]
+        file: PathBuf,
+    },
+    #[clap(
+        about = "Replicate the firmware projects to a directory that can be served as a mirror"
+    )]
+    MirrorSync {
+        #[clap(help = "Mirror directory to update")]
+        dir: PathBuf,
+    },
+    #[clap(about = "Serve the download cache over HTTP for other machines to download from")]
+    Serve {
+        #[clap(
+            help = "Address to listen on, such as 0.0.0.0:8080 to serve other machines",
+            long = "listen",
+            value_name = "ADDR",
+            default_value = "127.0.0.1:8080"
+        )]
+        listen: String,
+    },
+    #[clap(about = "Show installed firmware and update status")]
+    Status,
+    #[clap(about = "Update Thelio IO firmware")]
+    ThelioIo {
+        #[clap(subcommand)]
+        command: Option<ThelioIoCommand>,
+    },
+}
+
+#[derive(Subcommand)]
+enum ThelioIoCommand {
+    #[clap(about = "List Thelio IO devices and their firmware revisions")]
+    List,
+    #[clap(about = "Update Thelio IO firmware, on all devices by default")]
+    Update {
+        #[clap(
+            help = "Only update the device at this sysfs path",
+            long = "device",
+            value_name = "PATH"
+        )]
+        device: Option<PathBuf>,
+    },
+    #[clap(about = "Flash devices stuck in the bootloader using cached firmware")]
+    Recover,
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    #[clap(about = "List cached objects and the files they belong to")]
+    List,
+    #[clap(about = "Rehash cached objects and remove corrupt ones")]
+    Verify,
+    #[clap(about = "Remove objects not used by the current firmware")]
+    Prune,
+}
+
+/// The class of failure, reported in JSON output.
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ErrorKind {
+    Permission,
+    Config,
+    Daemon,
+    EfiMount,
+    FirmwareId,
+    Download,
+    Schedule,
+    Unschedule,
+    Update,
+    Cache,
+    Output,
+    Serve,
+    // The classes below come from the library or daemon, and replace the step that failed
+    Unsupported,
+    NotUefi,
+    Hardware,
+    Verification,
+    EfiVars,
+    Esp,
+    ThelioIo,
+}
+
+impl ErrorKind {
+    fn exit_code(self) -> i32 {
+        match self {
+            ErrorKind::Download => EXIT_DOWNLOAD_FAILED,
+            ErrorKind::Unsupported => EXIT_UNSUPPORTED,
+            ErrorKind::NotUefi => EXIT_NOT_UEFI,
+            ErrorKind::Hardware => EXIT_HARDWARE,
+            ErrorKind::Verification => EXIT_VERIFICATION_FAILED,
+            ErrorKind::EfiVars => EXIT_EFI_VARS,
+            ErrorKind::Esp => EXIT_ESP,
+            ErrorKind::ThelioIo => EXIT_THELIO_IO,
+            _ => EXIT_FAILURE,
+        }
+    }
+}
+
+/// A failure of the library or the daemon, with the class it was given there.
+struct Cause {
+    message: String,
+    kind: Option<ErrorKind>,
+    failure: Option<download::FailureKind>,
+}
+
+impl Cause {
+    fn download(message: String, failure: download::FailureKind) -> Self {
+        let kind = match failure {
+            download::FailureKind::Verification => ErrorKind::Verification,
+            _ => ErrorKind::Download,
+        };
+
+        Self {
+            message,
+            kind: Some(kind),
+            failure: Some(failure),
+        }
+    }
+}
+
+impl From<String> for Cause {
+    fn from(message: String) -> Self {
+        Self {
+            message,
+            kind: None,
+            failure: None,
+        }
+    }
+}
+
+impl From<system76_firmware::Error> for Cause {
+    fn from(err: system76_firmware::Error) -> Self {
+        use system76_firmware::Error as E;
+
+        let kind = match err {
+            E::Download(failure) => return Self::download(failure.message, failure.kind),
+            E::NotWhitelisted => ErrorKind::Unsupported,
+            E::NotUefi => ErrorKind::NotUefi,
+            E::Hardware { .. } => ErrorKind::Hardware,
+            E::EfiVars { .. } => ErrorKind::EfiVars,
+            E::Esp { .. } => ErrorKind::Esp,
+            E::ThelioIo { .. } => ErrorKind::ThelioIo,
+            E::Other(_) => return err.to_string().into(),
+        };
+
+        Self {
+            message: err.to_string(),
+            kind: Some(kind),
+            failure: None,
+        }
+    }
+}
+
+impl From<daemon::Error> for Cause {
+    fn from(err: daemon::Error) -> Self {
+        let name = err.name().map(String::from);
+        let message = daemon_err(err);
+
+        let kind = match name.as_deref() {
+            Some(daemon::ERROR_NETWORK) => {
+                return Self::download(message, download::FailureKind::Transient)
+            }
+            Some(daemon::ERROR_NOT_FOUND) => {
+                return Self::download(message, download::FailureKind::NotFound)
+            }
+            Some(daemon::ERROR_VERIFICATION) => {
+                return Self::download(message, download::FailureKind::Verification)
+            }
+            Some(daemon::ERROR_NOT_WHITELISTED) => ErrorKind::Unsupported,
+            Some(daemon::ERROR_NOT_UEFI) => ErrorKind::NotUefi,
+            Some(daemon::ERROR_HARDWARE) => ErrorKind::Hardware,
+            Some(daemon::ERROR_EFI_VARS) => ErrorKind::EfiVars,
+            Some(daemon::ERROR_ESP) => ErrorKind::Esp,
+            Some(daemon::ERROR_THELIO_IO) => ErrorKind::ThelioIo,
+            _ => return message.into(),
+        };
+
+        Self {
+            message,
+            kind: Some(kind),
+            failure: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Error {
+    kind: ErrorKind,
+    message: String,
+    /// How a download failed, after it was retried
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure: Option<download::FailureKind>,
+}
+
+impl Error {
+    fn new<S: Into<String>>(kind: ErrorKind, message: S) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            failure: None,
+        }
+    }
+
+    /// Reports `cause` after `context`, under the class of the cause if it has one.
+    fn wrap<C: Into<Cause>>(kind: ErrorKind, context: &str, cause: C) -> Self {
+        let cause = cause.into();
+        let message = match cause.failure {
+            Some(failure) => format!("{} ({}): {}", context, failure, cause.message),
+            None => format!("{}: {}", context, cause.message),
+        };
+
+        Self {
+            kind: cause.kind.unwrap_or(kind),
+            message,
+            failure: cause.failure,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// The result of a subcommand, printed as text or as a JSON document.
+trait Report: Serialize {
+    /// Prints the human readable form; progress is already reported on stderr.
+    fn print(&self) {}
+
+    /// The code the process exits with after reporting.
+    fn exit_code(&self) -> i32 {
+        EXIT_UP_TO_DATE
+    }
+}
+
+/// How firmware is read and scheduled.
+enum Backend {
+    /// Running as root, with direct access to the hardware and the ESP
+    Local,
+    /// Running unprivileged, through the system76-firmware daemon
+    Daemon(Client),
+}
+
+impl Backend {
+    fn require_root(&self) -> Result<(), Error> {
+        match self {
+            Backend::Local => Ok(()),
+            Backend::Daemon(_) => Err(Error::new(ErrorKind::Permission, "must be run as root")),
+        }
+    }
+
+    fn bios(&self) -> Result<(String, String), Cause> {
+        match self {
+            Backend::Local => Ok(bios()?),
+            Backend::Daemon(client) => client
+                .bios()
+                .map(|info| (info.model.into(), info.version.into()))
+                .map_err(Cause::from),
+        }
+    }
+
+    fn ec(&self, primary: bool) -> Result<(String, String), Cause> {
+        match self {
+            Backend::Local => Ok(ec(primary)?),
+            Backend::Daemon(client) => client
+                .embedded_control(primary)
+                .map(|info| (info.project.into(), info.version.into()))
+                .map_err(Cause::from),
+        }
+    }
+
+    fn me(&self) -> Result<Option<String>, Cause> {
+        match self {
+            Backend::Local => Ok(me()?),
+            Backend::Daemon(client) => client
+                .management_engine()
+                .map(|info| Some(info.version.into()).filter(|_| info.enabled))
+                .map_err(Cause::from),
+        }
+    }
+
+    fn firmware_id(&self, transition_kind: TransitionKind) -> Result<String, Cause> {
+        match (self, transition_kind) {
+            (Backend::Local, _) => Ok(firmware_id(transition_kind)?),
+            (Backend::Daemon(client), TransitionKind::Automatic) => client
+                .firmware_id()
+                .map(|id| id.to_string())
+                .map_err(Cause::from),
+            // The daemon only selects firmware automatically
+            (Backend::Daemon(_), _) => Err(Cause {
+                message: "must be run as root to select firmware".to_string(),
+                kind: Some(ErrorKind::Permission),
+                failure: None,
+            }),
+        }
+    }
+
+    fn thelio_io_list(&self) -> Result<HashMap<String, String>, Cause> {
+        match self {
+            Backend::Local => Ok(thelio_io_list()?),
+            Backend::Daemon(client) => client
+                .thelio_io_list()
+                .map(|list| list.0)
+                .map_err(Cause::from),
+        }
+    }
+}
+
+/// Formats a daemon error along with its causes, which hold the D-Bus error message.
+fn daemon_err(err: daemon::Error) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(": ");
+        message.push_str(&err.to_string());
+        source = err.source();
+    }
+    message
+}
+
+/// A value that is reported with the reason it could not be read.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Probe<T> {
+    Found(T),
+    Failed { error: String },
+}
+
+impl<T> From<Result<T, Cause>> for Probe<T> {
+    fn from(result: Result<T, Cause>) -> Self {
+        match result {
+            Ok(ok) => Probe::Found(ok),
+            Err(cause) => Probe::Failed {
+                error: cause.message,
+            },
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BiosStatus {
+    model: String,
+    version: String,
+}
+
+#[derive(Serialize)]
+struct EcStatus {
+    project: String,
+    version: String,
+}
+
+#[derive(Serialize)]
+struct MeStatus {
+    enabled: bool,
+    version: Option<String>,
+}
+
+#[derive(Serialize)]
+struct FirmwareIdStatus {
+    transition: String,
+    id: Probe<String>,
+}
+
+#[derive(Serialize)]
+struct ThelioIoDevice {
+    path: String,
+    bootloader: bool,
+    revision: Option<String>,
+}
+
+fn thelio_io_devices(backend: &Backend) -> Result<Vec<ThelioIoDevice>, Cause> {
+    let mut devices = backend
+        .thelio_io_list()?
+        .into_iter()
+        .map(|(path, revision)| ThelioIoDevice {
+            path,
+            bootloader: revision.is_empty(),
+            revision: Some(revision).filter(|revision| !revision.is_empty()),
+        })
+        .collect::<Vec<_>>();
+    devices.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(devices)
+}
+
+#[derive(Serialize)]
+struct StatusReport {
+    bios: Probe<BiosStatus>,
+    ec: Probe<EcStatus>,
+    ec2: Probe<EcStatus>,
+    me: Probe<MeStatus>,
+    firmware_ids: Vec<FirmwareIdStatus>,
+    thelio_io: Probe<Vec<ThelioIoDevice>>,
+    /// Unknown if no ESP is mounted, such as on systems booted in legacy mode
+    scheduled: Probe<Option<PathBuf>>,
+}
+
+impl Report for StatusReport {
+    fn print(&self) {
+        match &self.bios {
+            Probe::Found(bios) => {
+                println!("BIOS Model: {}", bios.model);
+                println!("BIOS Version: {}", bios.version);
+            }
+            Probe::Failed { error } => println!("BIOS: {}", error),
+        }
+
+        for (name, ec) in [("EC", &self.ec), ("EC2", &self.ec2)] {
+            match ec {
+                Probe::Found(ec) => {
+                    println!("{} Project: {}", name, ec.project);
+                    println!("{} Version: {}", name, ec.version);
+                }
+                Probe::Failed { .. } => println!("{}: none", name),
+            }
+        }
+
+        match &self.me {
+            Probe::Found(MeStatus {
+                version: Some(version),
+                ..
+            }) => println!("ME Version: {}", version),
+            Probe::Found(_) => println!("ME: disabled"),
+            Probe::Failed { error } => println!("ME: {}", error),
+        }
+
+        for firmware_id in &self.firmware_ids {
+            match &firmware_id.id {
+                Probe::Found(id) => println!("Firmware ID ({}): {}", firmware_id.transition, id),
+                Probe::Failed { error } => {
+                    println!("Firmware ID ({}): {}", firmware_id.transition, error)
+                }
+            }
+        }
+
+        match &self.thelio_io {
+            Probe::Found(devices) => {
+                for device in devices {
+                    match &device.revision {
+                        Some(revision) => {
+                            println!("Thelio Io: {} (revision {})", device.path, revision)
+                        }
+                        None => println!("Thelio Io: {} (bootloader)", device.path),
+                    }
+                }
+            }
+            Probe::Failed { error } => println!("Thelio Io: {}", error),
+        }
+
+        match &self.scheduled {
+            Probe::Found(Some(updater_dir)) => println!("Scheduled: {}", updater_dir.display()),
+            Probe::Found(None) => println!("Scheduled: none"),
+            Probe::Failed { error } => println!("Scheduled: unknown ({})", error),
+        }
+    }
+}
+
+fn status(backend: &Backend, efi_dir: Option<&str>) -> StatusReport {
+    StatusReport {
+        bios: backend
+            .bios()
+            .map(|(model, version)| BiosStatus { model, version })
+            .into(),
+        ec: backend
+            .ec(true)
+            .map(|(project, version)| EcStatus { project, version })
+            .into(),
+        ec2: backend
+            .ec(false)
+            .map(|(project, version)| EcStatus { project, version })
+            .into(),
+        me: backend
+            .me()
+            .map(|version| MeStatus {
+                enabled: version.is_some(),
+                version,
+            })
+            .into(),
+        firmware_ids: [
+            TransitionKind::Automatic,
+            TransitionKind::Open,
+            TransitionKind::Proprietary,
+        ]
+        .iter()
+        .map(|transition_kind| FirmwareIdStatus {
+            transition: format!("{:?}", transition_kind),
+            id: backend.firmware_id(*transition_kind).into(),
+        })
+        .collect(),
+        thelio_io: thelio_io_devices(backend).into(),
+        scheduled: match efi_dir {
+            Some(efi_dir) => Probe::Found(scheduled(efi_dir)),
+            None => Probe::Failed {
+                error: "EFI mount point not found".to_string(),
+            },
+        },
+    }
+}
+
+/// Firmware downloaded into the cache for this machine.
+struct Fetched {
+    firmware_id: String,
+    digest: String,
+    changelog: Changelog,
+    /// The digest as returned by the daemon, which is needed to schedule through it
+    daemon_digest: Option<Digest>,
+}
+
+fn fetch(
+    backend: &Backend,
+    transition_kind: TransitionKind,
+    bundle: Option<&Path>,
+    allow_downgrade: bool,
+) -> Result<Fetched, Error> {
+    let firmware_id = backend
+        .firmware_id(transition_kind)
+        .map_err(|err| Error::wrap(ErrorKind::FirmwareId, "failed to get firmware ID", err))?;
+
+    if let Backend::Daemon(client) = backend {
+        // The daemon does not import bundles, and always applies the rollback checks
+        if bundle.is_some() || allow_downgrade {
+            backend.require_root()?;
+        }
+
+        let info = client
+            .download()
+            .map_err(|err| Error::wrap(ErrorKind::Download, "failed to download", err))?;
+
+        return Ok(Fetched {
+            firmware_id,
+            digest: info.digest.to_string(),
+            changelog: info.changelog,
+            daemon_digest: Some(info.digest),
+        });
+    }
+
+    let (digest, changelog) = match bundle {
+        Some(bundle) => bundle_import(bundle, &firmware_id, allow_downgrade)
+            .map_err(Cause::from)
+            .and_then(|(digest, changelog)| Ok((digest, Changelog::parse(&changelog)?)))
+            .map_err(|err| Error::wrap(ErrorKind::Download, "failed to import bundle", err))?,
+        None => download_firmware_id(&firmware_id, allow_downgrade)
+            .map_err(Cause::from)
+            .and_then(|(digest, changelog)| Ok((digest, Changelog::parse(&changelog)?)))
+            .map_err(|err| Error::wrap(ErrorKind::Download, "failed to download", err))?,
+    };
+
+    Ok(Fetched {
+        firmware_id,
+        digest,
+        changelog,
+        daemon_digest: None,
+    })
+}
+
+#[derive(Serialize)]
+struct DownloadReport {
+    firmware_id: String,
+    digest: String,
+}
+
+impl Report for DownloadReport {
+    fn print(&self) {
+        println!("Downloaded {} ({})", self.firmware_id, self.digest);
+    }
+}
+
+#[derive(Serialize)]
+struct ChangelogEntry {
+    #[serde(flatten)]
+    version: Version,
+    newer: bool,
+}
+
+#[derive(Serialize)]
+struct ChangelogReport {
+    firmware_id: String,
+    digest: String,
+    installed: Option<String>,
+    /// Whether the installed BIOS is in the changelog, without which no entry is marked newer
+    listed: bool,
+    versions: Vec<ChangelogEntry>,
+}
+
+impl Report for ChangelogReport {
+    fn print(&self) {
+        println!("Firmware ID: {}", self.firmware_id);
+        match &self.installed {
+            Some(installed) if !self.listed => println!(
+                "Installed BIOS: {} (not listed, so newer versions cannot be marked)",
+                installed
+            ),
+            Some(installed) => println!("Installed BIOS: {}", installed),
+            None => println!("Installed BIOS: unknown"),
+        }
+
+        // Newer entries are marked, and shown in bold on a terminal
+        let (bold, reset) = if io::stdout().is_terminal() {
+            ("\x1B[1m", "\x1B[0m")
+        } else {
+            ("", "")
+        };
+
+        for entry in &self.versions {
+            let version = &entry.version;
+            println!();
+            if entry.newer {
+                print!("{}* ", bold);
+            } else {
+                print!("  ");
+            }
+            print!("{}  BIOS {}", version.date, version.bios);
+            if let Some(me) = &version.me {
+                print!("  ME {}", me);
+            }
+            if entry.newer {
+                print!(" (new){}", reset);
+            }
+            println!();
+
+            for line in version.description.lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+}
+
+fn changelog_report(backend: &Backend, fetched: Fetched) -> ChangelogReport {
+    let installed = backend.bios().ok().map(|(_model, version)| version);
+    let newer = installed
+        .as_ref()
+        .and_then(|installed| fetched.changelog.newer_than(installed));
+
+    ChangelogReport {
+        firmware_id: fetched.firmware_id,
+        digest: fetched.digest,
+        installed,
+        listed: newer.is_some(),
+        versions: fetched
+            .changelog
+            .versions
+            .into_iter()
+            .enumerate()
+            .map(|(i, version)| ChangelogEntry {
+                version,
+                newer: newer.is_some_and(|newer| i < newer),
+            })
+            .collect(),
+    }
+}
+
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum CheckStatus {
+    UpToDate,
+    UpdateAvailable,
+    Unsupported,
+    /// The installed BIOS is not in the changelog, such as a beta or a much older version
+    NotListed,
+}
+
+#[derive(Serialize)]
+struct CheckReport {
+    status: CheckStatus,
+    model: String,
+    installed: String,
+    firmware_id: Option<String>,
+    latest: Option<String>,
+}
+
+impl Report for CheckReport {
+    fn print(&self) {
+        match self.status {
+            CheckStatus::UpToDate => println!("Up to date: {}", self.installed),
+            CheckStatus::UpdateAvailable => println!(
+                "Update available: {} -> {}",
+                self.installed,
+                self.latest.as_deref().unwrap_or("unknown")
+            ),
+            CheckStatus::Unsupported => println!("Unsupported model: {}", self.model),
+            CheckStatus::NotListed => println!(
+                "Installed version not in changelog: {} (latest {})",
+                self.installed,
+                self.latest.as_deref().unwrap_or("unknown")
+            ),
+        }
+    }
+
+    fn exit_code(&self) -> i32 {
+        match self.status {
+            CheckStatus::UpToDate => EXIT_UP_TO_DATE,
+            CheckStatus::UpdateAvailable => EXIT_UPDATE_AVAILABLE,
+            CheckStatus::Unsupported => EXIT_UNSUPPORTED,
+            CheckStatus::NotListed => EXIT_NOT_LISTED,
+        }
+    }
+}
+
+fn check(
+    backend: &Backend,
+    transition_kind: TransitionKind,
+    allow_downgrade: bool,
+) -> Result<CheckReport, Error> {
+    let (model, installed) = backend
+        .bios()
+        .map_err(|err| Error::wrap(ErrorKind::FirmwareId, "failed to read BIOS", err))?;
+
+    if !model_is_whitelisted(&model) {
+        return Ok(CheckReport {
+            status: CheckStatus::Unsupported,
+            model,
+            installed,
+            firmware_id: None,
+            latest: None,
+        });
+    }
+
+    let fetched = fetch(backend, transition_kind, None, allow_downgrade)?;
+    let latest = fetched
+        .changelog
+        .versions
+        .first()
+        .map(|version| version.bios.clone());
+
+    // Only versions listed before the installed one are newer, so that a beta or otherwise newer
+    // BIOS is never reported as needing an update
+    let status = match fetched.changelog.newer_than(&installed) {
+        Some(0) => CheckStatus::UpToDate,
+        Some(_) => CheckStatus::UpdateAvailable,
+        None => CheckStatus::NotListed,
+    };
+
+    Ok(CheckReport {
+        status,
+        model,
+        installed,
+        firmware_id: Some(fetched.firmware_id),
+        latest,
+    })
+}
+
+#[derive(Serialize)]
+struct ScheduleReport {
+    firmware_id: String,
+    digest: String,
+    changelog: Vec<Version>,
+    scheduled: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dry_run: Option<EspChanges>,
+}
+
+impl Report for ScheduleReport {
+    fn print(&self) {
+        if let Some(changes) = &self.dry_run {
+            println!("Would replace {}", changes.updater_dir.display());
+            print_changes(changes, "Would write");
+        }
+    }
+}
+
+fn print_changes(changes: &EspChanges, action: &str) {
+    if !changes.files.is_empty() {
+        println!("{}:", action);
+        for file in &changes.files {
+            println!("  {}", file.display());
+        }
+    }
+
+    println!("Would run:");
+    for command in &changes.commands {
+        println!("  {}", command);
+    }
+}
+
+#[derive(Serialize)]
+struct ListReport {
+    firmware: Vec<FirmwareImage>,
+    #[serde(skip)]
+    changelog: bool,
+}
+
+impl Report for ListReport {
+    fn print(&self) {
+        if self.firmware.is_empty() {
+            println!("No firmware found");
+        }
+
+        for image in &self.firmware {
+            println!("{}", image.firmware_id);
+            println!("  Model: {}", image.model);
+            if image.projects.is_empty() {
+                println!("  EC Project: unknown");
+            } else {
+                println!("  EC Project: {}", image.projects.join(", "));
+            }
+            if !self.changelog {
+                continue;
+            }
+            match (&image.latest, &image.error) {
+                (Some(version), _) => println!("  Latest: {} ({})", version.bios, version.date),
+                (None, Some(err)) => println!("  Latest: unknown ({})", err),
+                (None, None) => println!("  Latest: none"),
+            }
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CacheListReport {
+    objects: Vec<CacheEntry>,
+}
+
+impl Report for CacheListReport {
+    fn print(&self) {
+        for object in &self.objects {
+            println!(
+                "{} {:>10} {}",
+                object.digest,
+                object.size,
+                object.references.join(", ")
+            );
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CacheVerifyReport {
+    valid: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl Report for CacheVerifyReport {
+    fn print(&self) {
+        println!(
+            "{} valid, {} corrupt objects removed",
+            self.valid.len(),
+            self.removed.len()
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct CachePruneReport {
+    removed: Vec<CacheEntry>,
+}
+
+impl Report for CachePruneReport {
+    fn print(&self) {
+        let size = self.removed.iter().map(|object| object.size).sum::<u64>();
+        println!(
+            "{} objects removed, {} bytes freed",
+            self.removed.len(),
+            size
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct ExportReport {
+    firmware_id: String,
+    digest: String,
+    bundle: PathBuf,
+}
+
+impl Report for ExportReport {
+    fn print(&self) {
+        println!("Exported {} to {}", self.firmware_id, self.bundle.display());
+    }
+}
+
+#[derive(Serialize)]
+struct MirrorSyncReport {
+    projects: Vec<MirrorProject>,
+}
+
+impl Report for MirrorSyncReport {
+    fn print(&self) {
+        for project in &self.projects {
+            match &project.previous {
+                Some(previous) if *previous == project.digest => {
+                    println!("{}: unchanged {}", project.project, project.digest)
+                }
+                Some(previous) => {
+                    println!("{}: {} -> {}", project.project, previous, project.digest)
+                }
+                None => println!("{}: new {}", project.project, project.digest),
+            }
+
+            for object in &project.added {
+                println!("  added {} ({})", object.file, object.digest);
+            }
+            println!("  {} objects already present", project.unchanged);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UnscheduleReport {
+    cancelled: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    dry_run: Option<EspChanges>,
+}
+
+impl Report for UnscheduleReport {
+    fn print(&self) {
+        if let Some(changes) = &self.dry_run {
+            print_changes(changes, "Would remove");
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ThelioIoReport {
+    digest: String,
+    revision: String,
+    devices: Vec<ThelioIoDevice>,
+}
+
+impl Report for ThelioIoReport {}
+
+#[derive(Serialize)]
+struct ThelioIoListReport {
+    revision: Option<String>,
+    devices: Vec<ThelioIoDevice>,
+}
+
+impl Report for ThelioIoListReport {
+    fn print(&self) {
+        let target = self.revision.as_deref().unwrap_or("unknown");
+        for device in &self.devices {
+            match &device.revision {
+                Some(revision) => println!(
+                    "{} normal revision {} (target {})",
+                    device.path, revision, target
+                ),
+                None => println!("{} bootloader (target {})", device.path, target),
+            }
+        }
+    }
+}
+
+fn output<R: Report>(json: bool, report: &R) -> Result<i32, Error> {
+    if json {
+        let document = serde_json::to_string_pretty(report).map_err(|err| {
+            Error::new(ErrorKind::Output, format!("failed to serialize: {}", err))
+        })?;
+        println!("{}", document);
+    } else {
+        report.print();
+    }
+
+    Ok(report.exit_code())
+}
+
+/// Draws the progress of a download as a bar on stderr, ending the line once it completes.
+fn draw_progress(progress: &download::Progress) {
+    const WIDTH: u64 = 30;
+
+    let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+    let mut stderr = io::stderr().lock();
+    let _ = match progress.total {
+        Some(total) if total > 0 => {
+            let received = progress.received.min(total);
+            let filled = (received * WIDTH / total) as usize;
+            write!(
+                stderr,
+                "\r  [{}{}] {:>3}% {:.1}/{:.1} MiB",
+                "#".repeat(filled),
+                " ".repeat(WIDTH as usize - filled),
+                received * 100 / total,
+                mib(received),
+                mib(total)
+            )
+        }
+        _ => write!(stderr, "\r  {:.1} MiB", mib(progress.received)),
+    };
+
+    if progress.total == Some(progress.received) {
+        let _ = writeln!(stderr);
+    }
+}
+
+fn tool(args: Args) -> Result<i32, Error> {
+    config::Config::init().map_err(|err| Error::new(ErrorKind::Config, err))?;
+
+    // Without root, go through the daemon, which allows members of the adm and sudo groups
+    let backend = if unsafe { libc::geteuid() } == 0 {
+        // Get I/O Permission
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        if unsafe { libc::iopl(3) } < 0 {
+            return Err(Error::new(
+                ErrorKind::Permission,
+                format!(
+                    "failed to get I/O permission: {}",
+                    io::Error::last_os_error()
+                ),
+            ));
+        }
+
+        download::secure_cache().map_err(|err| Error::new(ErrorKind::Cache, err))?;
+
+        if io::stderr().is_terminal() {
+            download::set_progress(draw_progress);
+        }
+
+        Backend::Local
+    } else {
+        let mut client = Client::new().map_err(|err| {
+            Error::new(
+                ErrorKind::Daemon,
+                format!("failed to connect to daemon: {}", daemon_err(err)),
+            )
+        })?;
+
+        // The daemon reports the progress of its own downloads with signals
+        if io::stderr().is_terminal() {
+            client.on_download_progress(draw_progress).map_err(|err| {
+                Error::new(
+                    ErrorKind::Daemon,
+                    format!("failed to connect to daemon: {}", daemon_err(err)),
+                )
+            })?;
+        }
+
+        Backend::Daemon(client)
+    };
+
+    let allow_downgrade = args.allow_downgrade;
+
+    let efi_dir = || {
+        util::get_efi_mnt()
+            .ok_or_else(|| Error::new(ErrorKind::EfiMount, "EFI mount point not found"))
+    };
+
+    match args.command {
+        Command::Check { transition } => output(
+            args.json,
+            &check(&backend, transition.kind(), allow_downgrade)?,
+        ),
+        Command::Download { transition } => {
+            let fetched = fetch(&backend, transition.kind(), None, allow_downgrade)?;
+
+            output(
+                args.json,
+                &DownloadReport {
+                    firmware_id: fetched.firmware_id,
+                    digest: fetched.digest,
+                },
+            )
+        }
+        Command::Changelog { transition } => {
+            let fetched = fetch(&backend, transition.kind(), None, allow_downgrade)?;
+
+            output(args.json, &changelog_report(&backend, fetched))
+        }
+        Command::Schedule {
+            transition,
+            bundle,
+            dry_run,
+        } => {
+            let schedule_err =
+                |err: Cause| Error::wrap(ErrorKind::Schedule, "failed to schedule", err);
+
+            if let Backend::Daemon(client) = &backend {
+                if dry_run {
+                    backend.require_root()?;
+                }
+
+                let fetched = fetch(
+                    &backend,
+                    transition.kind(),
+                    bundle.as_deref(),
+                    allow_downgrade,
+                )?;
+                let digest = fetched.daemon_digest.expect("fetched through the daemon");
+                client
+                    .schedule(&digest)
+                    .map_err(|err| schedule_err(err.into()))?;
+
+                // The ESP may not be readable without root
+                return output(
+                    args.json,
+                    &ScheduleReport {
+                        firmware_id: fetched.firmware_id,
+                        digest: fetched.digest,
+                        changelog: fetched.changelog.versions,
+                        scheduled: util::get_efi_mnt().and_then(|efi_dir| scheduled(&efi_dir)),
+                        dry_run: None,
+                    },
+                );
+            }
+
+            let efi_dir = efi_dir()?;
+            let fetched = fetch(
+                &backend,
+                transition.kind(),
+                bundle.as_deref(),
+                allow_downgrade,
+            )?;
+
+            let (scheduled, dry_run) = if dry_run {
+                let changes =
+                    schedule_firmware_id_dry_run(&fetched.digest, &efi_dir, &fetched.firmware_id)
+                        .map_err(|err| schedule_err(err.into()))?;
+                (None, Some(changes))
+            } else {
+                schedule_firmware_id(&fetched.digest, &efi_dir, &fetched.firmware_id)
+                    .map_err(|err| schedule_err(err.into()))?;
+                (scheduled(&efi_dir), None)
+            };
+
+            output(
+                args.json,
+                &ScheduleReport {
+                    firmware_id: fetched.firmware_id,
+                    digest: fetched.digest,
+                    changelog: fetched.changelog.versions,
+                    scheduled,
+                    dry_run,
+                },
+            )
+        }
+        Command::Unschedule { dry_run } => {
+            let unschedule_err =
+                |err: Cause| Error::wrap(ErrorKind::Unschedule, "failed to unschedule", err);
+
+            if let Backend::Daemon(client) = &backend {
+                if dry_run {
+                    backend.require_root()?;
+                }
+
+                let cancelled = util::get_efi_mnt().and_then(|efi_dir| scheduled(&efi_dir));
+                client
+                    .unschedule()
+                    .map_err(|err| unschedule_err(err.into()))?;
+
+                return output(
+                    args.json,
+                    &UnscheduleReport {
+                        cancelled,
+                        dry_run: None,
+                    },
+                );
+            }
+
+            let efi_dir = efi_dir()?;
+
+            let report = if dry_run {
+                UnscheduleReport {
+                    cancelled: None,
+                    dry_run: Some(
+                        unschedule_dry_run(&efi_dir).map_err(|err| unschedule_err(err.into()))?,
+                    ),
+                }
+            } else {
+                let cancelled = scheduled(&efi_dir);
+                unschedule(&efi_dir).map_err(|err| unschedule_err(err.into()))?;
+                UnscheduleReport {
+                    cancelled,
+                    dry_run: None,
+                }
+            };
+
+            output(args.json, &report)
+        }
+        Command::List { model, changelog } => {
+            // The cache is only readable by root
+            backend.require_root()?;
+
+            let firmware = firmware_list(model.as_deref(), changelog, allow_downgrade)
+                .map_err(|err| Error::wrap(ErrorKind::Download, "failed to list firmware", err))?;
+
+            output(
+                args.json,
+                &ListReport {
+                    firmware,
+                    changelog,
+                },
+            )
+        }
+        Command::Cache { command } => {
+            backend.require_root()?;
+
+            let cache_err = |action: &str, err: system76_firmware::Error| {
+                Error::wrap(
+                    ErrorKind::Cache,
+                    &format!("failed to {} cache", action),
+                    err,
+                )
+            };
+
+            match command {
+                CacheCommand::List => output(
+                    args.json,
+                    &CacheListReport {
+                        objects: cache_list().map_err(|err| cache_err("list", err))?,
+                    },
+                ),
+                CacheCommand::Verify => {
+                    let (valid, removed) =
+                        cache_verify().map_err(|err| cache_err("verify", err))?;
+                    output(args.json, &CacheVerifyReport { valid, removed })
+                }
+                CacheCommand::Prune => output(
+                    args.json,
+                    &CachePruneReport {
+                        removed: cache_prune().map_err(|err| cache_err("prune", err))?,
+                    },
+                ),
+            }
+        }
+        Command::Export {
+            firmware_id,
+            model,
+            ec_project,
+            file,
+        } => {
+            backend.require_root()?;
+
+            let firmware_id = match (firmware_id, model, ec_project) {
+                (Some(firmware_id), _, _) => firmware_id,
+                (None, Some(model), Some(ec_project)) => generate_firmware_id(&model, &ec_project),
+                _ => unreachable!("clap requires a firmware ID or a model and EC project"),
+            };
+
+            let digest = bundle_export(&firmware_id, &file, allow_downgrade)
+                .map_err(|err| Error::wrap(ErrorKind::Download, "failed to export", err))?;
+
+            output(
+                args.json,
+                &ExportReport {
+                    firmware_id,
+                    digest,
+                    bundle: file,
+                },
+            )
+        }
+        // Only writes to the mirror directory, so it needs no privileges
+        Command::MirrorSync { dir } => {
+            let projects = mirror_sync(&dir, allow_downgrade)
+                .map_err(|err| Error::wrap(ErrorKind::Download, "failed to sync mirror", err))?;
+
+            output(args.json, &MirrorSyncReport { projects })
+        }
+        Command::Serve { listen } => {
+            // The cache is only readable by root
+            backend.require_root()?;
+
+            serve(&listen).map_err(|err| Error::wrap(ErrorKind::Serve, "failed to serve", err))?;
+            Ok(0)
+        }
+        Command::Status => {
+            // The hardware is still reported without an ESP, which is when status is most needed
+            let efi_dir = util::get_efi_mnt();
+
+            output(args.json, &status(&backend, efi_dir.as_deref()))
+        }
+        Command::ThelioIo {
+            command: Some(ThelioIoCommand::List),
+        } => {
+            backend.require_root()?;
+
+            let revision = match thelio_io_download(allow_downgrade) {
+                Ok((_digest, revision)) => Some(revision),
+                Err(err) => {
+                    eprintln!("failed to download: {}", err);
+                    None
+                }
+            };
+
+            let devices = thelio_io_devices(&backend)
+                .map_err(|err| Error::wrap(ErrorKind::Update, "failed to list", err))?;
+
+            output(args.json, &ThelioIoListReport { revision, devices })
+        }
+        Command::ThelioIo {
+            command: Some(ThelioIoCommand::Recover),
+        } => {
+            backend.require_root()?;
+
+            let (digest, revision) = thelio_io_recover()
+                .map_err(|err| Error::wrap(ErrorKind::Update, "failed to recover", err))?;
+
+            let devices = thelio_io_devices(&backend)
+                .map_err(|err| Error::wrap(ErrorKind::Update, "failed to recover", err))?;
+
+            output(
+                args.json,
+                &ThelioIoReport {
+                    digest,
+                    revision,
+                    devices,
+                },
+            )
+        }
+        Command::ThelioIo { command } => {
+            backend.require_root()?;
+
+            let device = match command {
+                Some(ThelioIoCommand::Update { device }) => device,
+                _ => None,
+            };
+
+            let (digest, revision) = thelio_io_download(allow_downgrade)
+                .map_err(|err| Error::wrap(ErrorKind::Download, "failed to download", err))?;
+
+            thelio_io_update_device(&digest, device.as_deref())
+                .map_err(|err| Error::wrap(ErrorKind::Update, "failed to update", err))?;
+
+            let devices = thelio_io_devices(&backend)
+                .map_err(|err| Error::wrap(ErrorKind::Update, "failed to update", err))?;
+
+            output(
+                args.json,
+                &ThelioIoReport {
+                    digest,
+                    revision,
+                    devices,
+                },
+            )
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorReport<'a> {
+    error: &'a Error,
+}
+
+impl Report for ErrorReport<'_> {}
+
+fn main() {
+    let args = Args::parse();
+    let json = args.json;
+
+    match tool(args) {
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            if json {
+                let _ = output(json, &ErrorReport { error: &err });
+            }
+            eprintln!("system76-firmware: {}", err);
+            process::exit(err.kind.exit_code());
+        }
+    }
+}
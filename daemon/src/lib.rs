@@ -1,13 +1,15 @@
 #[macro_use]
 extern crate thiserror;
 #[macro_use]
-extern crate serde;
-#[macro_use]
 extern crate shrinkwraprs;
 
+use dbus::message::{MatchRule, MessageType};
 use dbus::{ffidisp::Connection, Message};
 use std::collections::HashMap;
 
+pub use system76_firmware::changelog::{Changelog, Version};
+pub use system76_firmware::download::Progress;
+
 pub const DBUS_DEST: &str = "com.system76.FirmwareDaemon";
 pub const DBUS_IFACE: &str = DBUS_DEST;
 pub const DBUS_PATH: &str = "/com/system76/FirmwareDaemon";
@@ -23,6 +25,29 @@ pub const METHOD_THELIO_IO_LIST: &str = "ThelioIoList";
 pub const METHOD_THELIO_IO_UPDATE: &str = "ThelioIoUpdate";
 pub const METHOD_UNSCHEDULE: &str = "Unschedule";
 
+/// Emitted while the daemon downloads an object, with the name of the object, the bytes received,
+/// and the total size, which is zero if it is not known.
+pub const SIGNAL_DOWNLOAD_PROGRESS: &str = "DownloadProgress";
+
+/// The product is not one that System76 firmware is published for.
+pub const ERROR_NOT_WHITELISTED: &str = "com.system76.FirmwareDaemon.Error.NotWhitelisted";
+/// The BIOS, EC, or ME could not be read.
+pub const ERROR_HARDWARE: &str = "com.system76.FirmwareDaemon.Error.Hardware";
+/// A download failed in a way that may succeed if it is tried again.
+pub const ERROR_NETWORK: &str = "com.system76.FirmwareDaemon.Error.Network";
+/// The firmware or metadata requested is not published.
+pub const ERROR_NOT_FOUND: &str = "com.system76.FirmwareDaemon.Error.NotFound";
+/// Downloaded data did not match its signature or digest.
+pub const ERROR_VERIFICATION: &str = "com.system76.FirmwareDaemon.Error.Verification";
+/// Updates can only be scheduled when booted with UEFI.
+pub const ERROR_NOT_UEFI: &str = "com.system76.FirmwareDaemon.Error.NotUefi";
+/// The EFI boot variables could not be changed.
+pub const ERROR_EFI_VARS: &str = "com.system76.FirmwareDaemon.Error.EfiVars";
+/// The updater could not be written to or removed from the ESP.
+pub const ERROR_ESP: &str = "com.system76.FirmwareDaemon.Error.Esp";
+/// A Thelio Io device could not be found or flashed.
+pub const ERROR_THELIO_IO: &str = "com.system76.FirmwareDaemon.Error.ThelioIo";
+
 /// An error that may occur when interacting with the system76-firmware daemon.
 #[derive(Debug, Error)]
 pub enum Error {
@@ -41,16 +66,58 @@ pub enum Error {
     /// Failed to create a new method call.
     #[error("failed to create {} method call: {}", _0, _1)]
     NewMethodCall(&'static str, Box<str>),
+    /// Failed to subscribe to one of the daemon's signals.
+    #[error("subscribing to {} signal failed", _0)]
+    Subscribe(&'static str, #[source] dbus::Error),
 }
 
+impl Error {
+    /// The DBus error name returned by the daemon, if a method call failed.
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Error::Call(_, why) => why.name(),
+            _ => None,
+        }
+    }
+}
+
+type ProgressCallback = Box<dyn Fn(&Progress)>;
+
 /// DBus client connection for interacting with the system76-firmware daemon.
-pub struct Client(Connection);
+pub struct Client {
+    connection: Connection,
+    progress: Option<(MatchRule<'static>, ProgressCallback)>,
+}
 
 impl Client {
     pub fn new() -> Result<Self, Error> {
         Connection::new_system()
             .map_err(Error::Connection)
-            .map(Self)
+            .map(|connection| Self {
+                connection,
+                progress: None,
+            })
+    }
+
+    /// Calls `callback` with the progress of the daemon's downloads, as it signals them while
+    /// method calls are waiting for their reply. Replaces any callback set before.
+    pub fn on_download_progress<F: Fn(&Progress) + 'static>(
+        &mut self,
+        callback: F,
+    ) -> Result<(), Error> {
+        match self.progress.as_mut() {
+            Some((_, progress)) => *progress = Box::new(callback),
+            None => {
+                let rule = MatchRule::new_signal(DBUS_IFACE, SIGNAL_DOWNLOAD_PROGRESS)
+                    .with_sender(DBUS_DEST)
+                    .with_path(DBUS_PATH);
+                self.connection
+                    .add_match(&rule.match_str())
+                    .map_err(|why| Error::Subscribe(SIGNAL_DOWNLOAD_PROGRESS, why))?;
+                self.progress = Some((rule, Box::new(callback)));
+            }
+        }
+        Ok(())
     }
 
     /// Retrieves information about the BIOS currently installed on the system.
@@ -169,9 +236,51 @@ impl Client {
 
         m = append_args(m);
 
-        self.0
-            .send_with_reply_and_block(m, -1)
-            .map_err(|why| Error::Call(method, why))
+        let (rule, callback) = match self.progress.as_ref() {
+            Some((rule, callback)) => (rule, callback),
+            None => {
+                return self
+                    .connection
+                    .send_with_reply_and_block(m, -1)
+                    .map_err(|why| Error::Call(method, why))
+            }
+        };
+
+        // Signals are only received while waiting for the reply if the connection is processed
+        let serial = self.connection.send(m).map_err(|()| {
+            Error::Call(
+                method,
+                dbus::Error::new_failed("failed to send method call"),
+            )
+        })?;
+
+        loop {
+            for mut message in self.connection.incoming(1000) {
+                if message.msg_type() == MessageType::Signal {
+                    if rule.matches(&message) {
+                        if let Ok((name, received, total)) = message.read3::<String, u64, u64>() {
+                            callback(&Progress {
+                                name: &name,
+                                received,
+                                total: Some(total).filter(|&total| total != 0),
+                            });
+                        }
+                    }
+                } else if message.get_reply_serial() == Some(serial) {
+                    message
+                        .as_result()
+                        .map_err(|why| Error::Call(method, why))?;
+                    return Ok(message);
+                }
+            }
+
+            if !self.connection.is_connected() {
+                return Err(Error::Call(
+                    method,
+                    dbus::Error::new_failed("disconnected from the system bus"),
+                ));
+            }
+        }
     }
 }
 
@@ -182,21 +291,6 @@ pub struct BiosInfo {
     pub version: Box<str>,
 }
 
-/// Changelog containing details about each version of firmware.
-#[derive(Clone, Debug, Deserialize)]
-pub struct Changelog {
-    pub versions: Vec<Version>,
-}
-
-/// Details about a version of firmware.
-#[derive(Clone, Debug, Deserialize)]
-pub struct Version {
-    pub date: Box<str>,
-    pub bios: Box<str>,
-    pub me: Option<Box<str>>,
-    pub description: Box<str>,
-}
-
 /// Signature of the firmware that can be installed on the system.
 #[derive(Clone, Debug, Shrinkwrap)]
 pub struct Digest(Box<str>);
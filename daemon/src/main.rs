@@ -1,7 +1,13 @@
-use dbus::blocking::Connection;
+use dbus::blocking::SyncConnection;
+use dbus::channel::{MatchingReceiver, Sender};
+use dbus::message::MatchRule;
+use dbus::Message;
 use dbus_crossroads::{Context, Crossroads, MethodErr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{io, process};
 
+use system76_firmware::Error;
 use system76_firmware::*;
 use system76_firmware_daemon::*;
 
@@ -12,6 +18,29 @@ fn dmi_vendor() -> Result<String, String> {
     }
 }
 
+/// Logs a failed method call and returns it under the DBus error name of its class.
+fn method_err(err: Error) -> MethodErr {
+    eprintln!("{}", err);
+
+    let name = match &err {
+        Error::NotWhitelisted => ERROR_NOT_WHITELISTED,
+        Error::Hardware { .. } => ERROR_HARDWARE,
+        Error::Download(failure) => match failure.kind {
+            download::FailureKind::Transient => ERROR_NETWORK,
+            download::FailureKind::NotFound => ERROR_NOT_FOUND,
+            download::FailureKind::Verification => ERROR_VERIFICATION,
+            download::FailureKind::Other => return MethodErr::failed(&err),
+        },
+        Error::NotUefi => ERROR_NOT_UEFI,
+        Error::EfiVars { .. } => ERROR_EFI_VARS,
+        Error::Esp { .. } => ERROR_ESP,
+        Error::ThelioIo { .. } => ERROR_THELIO_IO,
+        Error::Other(_) => return MethodErr::failed(&err),
+    };
+
+    MethodErr::from((name, err.to_string()))
+}
+
 fn daemon() -> Result<(), String> {
     if unsafe { libc::geteuid() } != 0 {
         return Err("must be run as root".into());
@@ -26,6 +55,10 @@ fn daemon() -> Result<(), String> {
         ));
     }
 
+    config::Config::init()?;
+
+    download::secure_cache()?;
+
     /// State shared across DBus calls
     struct State {
         efi_dir: String,
@@ -39,21 +72,39 @@ fn daemon() -> Result<(), String> {
             None => return Err("EFI mount point not found".into()),
         },
 
-        in_whitelist: dmi_vendor()
-            .ok()
-            .map_or(false, |vendor| vendor.contains("System76"))
-            && bios()
-                .ok()
-                .map_or(false, |(model, _)| model_is_whitelisted(&*model)),
+        in_whitelist: dmi_vendor().is_ok_and(|vendor| vendor.contains("System76"))
+            && bios().is_ok_and(|(model, _)| model_is_whitelisted(&model)),
 
         transition_kind: TransitionKind::Automatic,
     };
 
-    let c = Connection::new_system().map_err(err_str)?;
+    let c = Arc::new(SyncConnection::new_system().map_err(err_str)?);
 
     c.request_name(DBUS_DEST, false, true, false)
         .map_err(err_str)?;
 
+    // Messages sent by method handlers are only flushed once they return, so download progress
+    // is sent and flushed directly while a method runs. It goes through the connection that owns
+    // the daemon's name, so that clients and the bus policy recognize its sender.
+    let signals = c.clone();
+    download::set_progress(move |progress| {
+        let message = match Message::new_signal(DBUS_PATH, DBUS_IFACE, SIGNAL_DOWNLOAD_PROGRESS) {
+            Ok(message) => message.append3(
+                progress.name,
+                progress.received,
+                progress.total.unwrap_or(0),
+            ),
+            Err(err) => {
+                eprintln!("failed to create progress signal: {}", err);
+                return;
+            }
+        };
+
+        if signals.send(message).is_ok() {
+            signals.channel().flush();
+        }
+    });
+
     let mut cr = Crossroads::new();
 
     let iface_token = cr.register(DBUS_IFACE, |b| {
@@ -64,13 +115,10 @@ fn daemon() -> Result<(), String> {
             move |_ctx: &mut Context, state: &mut State, _inputs: ()| {
                 eprintln!("Bios");
                 if !state.in_whitelist {
-                    return Err(MethodErr::failed(&"product is not in whitelist"));
+                    return Err(method_err(Error::NotWhitelisted));
                 }
 
-                bios().map_err(|err| {
-                    eprintln!("{}", err);
-                    MethodErr::failed(&err)
-                })
+                bios().map_err(method_err)
             },
         );
 
@@ -81,13 +129,10 @@ fn daemon() -> Result<(), String> {
             |_ctx: &mut Context, state: &mut State, (primary,): (bool,)| {
                 eprintln!("EmbeddedController({})", primary);
                 if !state.in_whitelist {
-                    return Err(MethodErr::failed(&"product is not in whitelist"));
+                    return Err(method_err(Error::NotWhitelisted));
                 }
 
-                ec(primary).map_err(|err| {
-                    eprintln!("{}", err);
-                    MethodErr::failed(&err)
-                })
+                ec(primary).map_err(method_err)
             },
         );
 
@@ -98,16 +143,13 @@ fn daemon() -> Result<(), String> {
             |_ctx: &mut Context, state: &mut State, _inputs: ()| {
                 eprintln!("ManagementEngine");
                 if !state.in_whitelist {
-                    return Err(MethodErr::failed(&"product is not in whitelist"));
+                    return Err(method_err(Error::NotWhitelisted));
                 }
 
                 match me() {
                     Ok(Some(me_version)) => Ok((true, me_version)),
                     Ok(None) => Ok((false, String::new())),
-                    Err(err) => {
-                        eprintln!("{}", err);
-                        Err(MethodErr::failed(&err))
-                    }
+                    Err(err) => Err(method_err(err)),
                 }
             },
         );
@@ -119,15 +161,12 @@ fn daemon() -> Result<(), String> {
             |_ctx: &mut Context, state: &mut State, _inputs: ()| {
                 eprintln!("FirmwareId");
                 if !state.in_whitelist {
-                    return Err(MethodErr::failed(&"product is not in whitelist"));
+                    return Err(method_err(Error::NotWhitelisted));
                 }
 
                 firmware_id(state.transition_kind)
                     .map(|v| (v,))
-                    .map_err(|err| {
-                        eprintln!("{}", err);
-                        MethodErr::failed(&err)
-                    })
+                    .map_err(method_err)
             },
         );
 
@@ -138,13 +177,10 @@ fn daemon() -> Result<(), String> {
             |_ctx: &mut Context, state: &mut State, _inputs: ()| {
                 eprintln!("Download");
                 if !state.in_whitelist {
-                    return Err(MethodErr::failed(&"product is not in whitelist"));
+                    return Err(method_err(Error::NotWhitelisted));
                 }
 
-                download(state.transition_kind).map_err(|err| {
-                    eprintln!("{}", err);
-                    MethodErr::failed(&err)
-                })
+                download(state.transition_kind).map_err(method_err)
             },
         );
 
@@ -155,13 +191,10 @@ fn daemon() -> Result<(), String> {
             |_ctx: &mut Context, state: &mut State, (digest,): (String,)| {
                 eprintln!("Schedule({})", digest);
                 if !state.in_whitelist {
-                    return Err(MethodErr::failed(&"product is not in whitelist"));
+                    return Err(method_err(Error::NotWhitelisted));
                 }
 
-                schedule(&digest, &state.efi_dir, state.transition_kind).map_err(|err| {
-                    eprintln!("{}", err);
-                    MethodErr::failed(&err)
-                })
+                schedule(&digest, &state.efi_dir, state.transition_kind).map_err(method_err)
             },
         );
 
@@ -172,13 +205,10 @@ fn daemon() -> Result<(), String> {
             |_ctx: &mut Context, state: &mut State, _inputs: ()| {
                 eprintln!("Unschedule");
                 if !state.in_whitelist {
-                    return Err(MethodErr::failed(&"product is not in whitelist"));
+                    return Err(method_err(Error::NotWhitelisted));
                 }
 
-                unschedule(&state.efi_dir).map_err(|err| {
-                    eprintln!("{}", err);
-                    MethodErr::failed(&err)
-                })
+                unschedule(&state.efi_dir).map_err(method_err)
             },
         );
 
@@ -189,10 +219,7 @@ fn daemon() -> Result<(), String> {
             |_ctx: &mut Context, _state: &mut State, _inputs: ()| {
                 eprintln!("ThelioIoDownload");
 
-                thelio_io_download().map_err(|err| {
-                    eprintln!("{}", err);
-                    MethodErr::failed(&err)
-                })
+                thelio_io_download(false).map_err(method_err)
             },
         );
 
@@ -203,10 +230,7 @@ fn daemon() -> Result<(), String> {
             |_ctx: &mut Context, _state: &mut State, _inputs: ()| {
                 eprintln!("ThelioIoList");
 
-                thelio_io_list().map(|v| (v,)).map_err(|err| {
-                    eprintln!("{}", err);
-                    MethodErr::failed(&err)
-                })
+                thelio_io_list().map(|v| (v,)).map_err(method_err)
             },
         );
 
@@ -217,17 +241,27 @@ fn daemon() -> Result<(), String> {
             |_ctx: &mut Context, _state: &mut State, (digest,): (String,)| {
                 eprintln!("ThelioIoUpdate({})", digest);
 
-                thelio_io_update(&digest).map_err(|err| {
-                    eprintln!("{}", err);
-                    MethodErr::failed(&err)
-                })
+                thelio_io_update(&digest).map_err(method_err)
             },
         );
     });
 
     cr.insert(DBUS_PATH, &[iface_token], state);
 
-    cr.serve(&c).map_err(err_str)
+    // Crossroads::serve only takes a connection that can not be shared with the progress callback
+    let cr = Mutex::new(cr);
+    c.start_receive(
+        MatchRule::new_method_call(),
+        Box::new(move |msg, conn| {
+            let mut cr = cr.lock().unwrap_or_else(|err| err.into_inner());
+            cr.handle_message(msg, conn).unwrap();
+            true
+        }),
+    );
+
+    loop {
+        c.process(Duration::from_millis(1000)).map_err(err_str)?;
+    }
 }
 
 fn main() {
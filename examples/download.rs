@@ -4,9 +4,9 @@ use system76_firmware::{config, download};
 fn inner() -> Result<(), String> {
     let dl = Downloader::new(
         config::KEY,
-        config::URL,
-        config::PROJECT,
-        config::BRANCH,
+        config::url(),
+        config::project(),
+        config::branch(),
         Some(config::CERT),
     )?;
 
@@ -14,7 +14,7 @@ fn inner() -> Result<(), String> {
     let tail = dl.tail()?;
 
     eprintln!("opening download cache");
-    let cache = download::Cache::new(config::CACHE, Some(dl))?;
+    let cache = download::Cache::new(config::cache(), Some(dl))?;
 
     eprintln!("downloading manifest.json");
     let manifest_json = cache.object(&tail.digest)?;
@@ -1,6 +1,6 @@
-use system76_firmware::{thelio_io_download, thelio_io_update};
+use system76_firmware::{thelio_io_download, thelio_io_update, Error};
 
-fn main() -> Result<(), String> {
-    let (digest, _revision) = thelio_io_download()?;
+fn main() -> Result<(), Error> {
+    let (digest, _revision) = thelio_io_download(false)?;
     thelio_io_update(&digest)
 }
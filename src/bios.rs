@@ -1,17 +1,17 @@
-use crate::util;
+use crate::{util, Error};
 
-pub fn bios() -> Result<(String, String), String> {
+pub fn bios() -> Result<(String, String), Error> {
     let bios_model = match util::read_string("/sys/class/dmi/id/product_version") {
         Ok(ok) => ok.trim().to_string(),
         Err(err) => {
-            return Err(format!("failed to read BIOS model: {}", err));
+            return Err(Error::hardware("failed to read BIOS model", err));
         }
     };
 
     let bios_version = match util::read_string("/sys/class/dmi/id/bios_version") {
         Ok(ok) => ok.trim().to_string(),
         Err(err) => {
-            return Err(format!("failed to read BIOS version: {}", err));
+            return Err(Error::hardware("failed to read BIOS version", err));
         }
     };
 
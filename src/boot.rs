@@ -4,7 +4,13 @@ use std::{fs, process};
 use crate::mount;
 use crate::util;
 
-pub fn set_next_boot(efi_dir: &str, modify_order: bool) -> Result<(), String> {
+/// Adds the updater boot entry and sets it as the next boot, returning the `efibootmgr`
+/// invocations. With `dry_run`, the invocations are only reported.
+pub fn set_next_boot(
+    efi_dir: &str,
+    modify_order: bool,
+    dry_run: bool,
+) -> Result<Vec<String>, String> {
     let mounts = match mount::Mount::all() {
         Ok(ok) => ok,
         Err(err) => {
@@ -74,7 +80,9 @@ pub fn set_next_boot(efi_dir: &str, modify_order: bool) -> Result<(), String> {
 
     let disk_dev = Path::new("/dev").join(disk_name);
 
-    println!("{} {}", disk_dev.display(), efi_part);
+    eprintln!("{} {}", disk_dev.display(), efi_part);
+
+    let mut commands = Vec::new();
 
     {
         let mut command = process::Command::new("efibootmgr");
@@ -97,15 +105,18 @@ pub fn set_next_boot(efi_dir: &str, modify_order: bool) -> Result<(), String> {
             .arg("system76-firmware-update");
 
         eprintln!("{:?}", command);
-
-        match command.status() {
-            Ok(status) => {
-                if !status.success() {
-                    return Err(format!("failed to add boot entry: {}", status));
+        commands.push(format!("{:?}", command));
+
+        if !dry_run {
+            match command.status() {
+                Ok(status) => {
+                    if !status.success() {
+                        return Err(format!("failed to add boot entry: {}", status));
+                    }
+                }
+                Err(err) => {
+                    return Err(format!("failed to add boot entry: {}", err));
                 }
-            }
-            Err(err) => {
-                return Err(format!("failed to add boot entry: {}", err));
             }
         }
     }
@@ -115,33 +126,43 @@ pub fn set_next_boot(efi_dir: &str, modify_order: bool) -> Result<(), String> {
         command.arg("--quiet").arg("--bootnext").arg("1776");
 
         eprintln!("{:?}", command);
-
-        match command.status() {
-            Ok(status) => {
-                if !status.success() {
-                    return Err(format!("failed to set next boot: {}", status));
+        commands.push(format!("{:?}", command));
+
+        if !dry_run {
+            match command.status() {
+                Ok(status) => {
+                    if !status.success() {
+                        return Err(format!("failed to set next boot: {}", status));
+                    }
+                }
+                Err(err) => {
+                    return Err(format!("failed to set next boot: {}", err));
                 }
-            }
-            Err(err) => {
-                return Err(format!("failed to set next boot: {}", err));
             }
         }
     }
 
-    Ok(())
+    Ok(commands)
 }
 
-pub fn unset_next_boot() -> Result<(), String> {
+/// Removes the next boot and the updater boot entry, returning the `efibootmgr` invocations.
+/// With `dry_run`, the invocations are only reported.
+pub fn unset_next_boot(dry_run: bool) -> Result<Vec<String>, String> {
+    let mut commands = Vec::new();
+
     {
         let mut command = process::Command::new("efibootmgr");
         command.arg("--quiet").arg("--delete-bootnext");
 
         eprintln!("{:?}", command);
+        commands.push(format!("{:?}", command));
 
-        match command.status() {
-            Ok(_status) => (),
-            Err(err) => {
-                return Err(format!("failed to unset next boot: {}", err));
+        if !dry_run {
+            match command.status() {
+                Ok(_status) => (),
+                Err(err) => {
+                    return Err(format!("failed to unset next boot: {}", err));
+                }
             }
         }
     }
@@ -155,14 +176,17 @@ pub fn unset_next_boot() -> Result<(), String> {
             .arg("1776");
 
         eprintln!("{:?}", command);
+        commands.push(format!("{:?}", command));
 
-        match command.status() {
-            Ok(_status) => (),
-            Err(err) => {
-                return Err(format!("failed to remove boot entry: {}", err));
+        if !dry_run {
+            match command.status() {
+                Ok(_status) => (),
+                Err(err) => {
+                    return Err(format!("failed to remove boot entry: {}", err));
+                }
             }
         }
     }
 
-    Ok(())
+    Ok(commands)
 }
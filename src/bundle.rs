@@ -0,0 +1,147 @@
+use buildchain::Manifest;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+use tar::{Archive, Builder, Header};
+
+use crate::tail::{SignedTail, BLOCK_SIZE};
+use crate::trust::Trust;
+use crate::{config, download, Error};
+
+/// Path of the signed tail block within a bundle.
+const TAIL: &str = "tail";
+
+/// Directory of objects within a bundle, named by digest as on the buildchain server.
+const OBJECT_DIR: &str = "object";
+
+/// Imports an offline firmware bundle into the download cache.
+///
+/// The tail block is verified with the trusted keys before any object is trusted, and each object
+/// is checked against its digest, so the result is the same as a download from the configured URL.
+/// Objects are streamed into the cache directory as they are read, and only moved into place once
+/// `accept_tail` has accepted the tail, with `allow_downgrade`.
+pub fn bundle_import<P: AsRef<Path>>(
+    path: P,
+    firmware_id: &str,
+    allow_downgrade: bool,
+) -> Result<(String, String), Error> {
+    let path = path.as_ref();
+
+    eprintln!("reading bundle {}", path.display());
+    let file = File::open(path)
+        .map_err(|err| Error::other(format!("failed to open {}: {}", path.display(), err)))?;
+
+    let cache = download::Cache::new(config::cache(), None).map_err(Error::other)?;
+
+    let mut signed_tail = None;
+    let mut objects = Vec::new();
+    let mut archive = Archive::new(file);
+    for entry_res in archive.entries().map_err(Error::other)? {
+        let mut entry = entry_res.map_err(Error::other)?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+
+        let entry_path = entry.path().map_err(Error::other)?.into_owned();
+        if entry_path == Path::new(TAIL) {
+            // Anything longer than a tail block fails to verify, so no more is read
+            let mut data = Vec::new();
+            (&mut entry)
+                .take(BLOCK_SIZE as u64 + 1)
+                .read_to_end(&mut data)
+                .map_err(Error::other)?;
+            signed_tail = Some(SignedTail::new(data));
+        } else if let Ok(name) = entry_path.strip_prefix(OBJECT_DIR) {
+            let digest = name.to_str().ok_or_else(|| {
+                Error::other(format!("invalid object path: {}", entry_path.display()))
+            })?;
+            objects.push(cache.stage(digest, &mut entry).map_err(Error::other)?);
+        }
+    }
+
+    eprintln!("verifying tail");
+    let signed_tail =
+        signed_tail.ok_or_else(|| Error::other("bundle does not contain a tail block"))?;
+    let tail = Trust::load().map_err(Error::other)?.verify(&signed_tail)?;
+
+    crate::accept_tail(config::project(), &tail, allow_downgrade)?;
+
+    eprintln!("importing {} objects", objects.len());
+    for object in objects {
+        object.commit().map_err(Error::other)?;
+    }
+
+    let changelog = crate::firmware_changelog(&cache, &tail.digest, firmware_id)?;
+
+    Ok((tail.digest, changelog))
+}
+
+/// Exports the signed tail, manifest, updater, and firmware for `firmware_id` to an offline
+/// bundle, returning the digest of the manifest. The tail is checked as by `accept_tail`.
+pub fn bundle_export<P: AsRef<Path>>(
+    firmware_id: &str,
+    path: P,
+    allow_downgrade: bool,
+) -> Result<String, Error> {
+    let path = path.as_ref();
+
+    let trust = Trust::load().map_err(Error::other)?;
+
+    eprintln!("downloading tail");
+    let signed_tail = trust.download_tail(config::project(), config::branch())?;
+    let tail = trust.verify(&signed_tail)?;
+
+    eprintln!("opening download cache");
+    let cache =
+        download::Cache::with_sources(config::cache(), trust.sources().map_err(Error::other)?)
+            .map_err(Error::other)?;
+    crate::accept_tail(config::project(), &tail, allow_downgrade)?;
+
+    eprintln!("downloading manifest.json");
+    let manifest_json = cache.object_named(&tail.digest, "manifest.json")?;
+    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(Error::other)?;
+
+    let mut digests = vec![tail.digest.clone()];
+    for file in [
+        "system76-firmware-update.tar.xz".to_string(),
+        format!("{}.tar.xz", firmware_id),
+    ] {
+        eprintln!("downloading {}", file);
+        let digest = manifest
+            .files
+            .get(&file)
+            .ok_or_else(|| Error::other(format!("{} not found", file)))?;
+        cache.open_named(digest, &file)?;
+        digests.push(digest.clone());
+    }
+
+    eprintln!("writing bundle {}", path.display());
+    let file = File::create(path)
+        .map_err(|err| Error::other(format!("failed to create {}: {}", path.display(), err)))?;
+
+    let mut builder = Builder::new(file);
+    let tail_data = signed_tail.as_bytes();
+    append(&mut builder, TAIL, tail_data.len() as u64, tail_data).map_err(Error::other)?;
+    for digest in &digests {
+        // Objects are streamed from the cache, as firmware archives can be large
+        let object = cache.open(digest)?;
+        let size = object.metadata().map_err(Error::other)?.len();
+        append(
+            &mut builder,
+            &format!("{}/{}", OBJECT_DIR, digest),
+            size,
+            object,
+        )
+        .map_err(Error::other)?;
+    }
+    builder.finish().map_err(Error::other)?;
+
+    Ok(tail.digest)
+}
+
+fn append<R: Read>(builder: &mut Builder<File>, path: &str, size: u64, data: R) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_size(size);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, path, data)
+}
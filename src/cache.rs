@@ -0,0 +1,155 @@
+use buildchain::Manifest;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::download::Failure;
+use crate::trust::Trust;
+use crate::{config, download, err_str, util, Error};
+
+/// An object in the download cache.
+#[derive(Clone, Debug, Serialize)]
+pub struct CacheEntry {
+    pub digest: String,
+    pub size: u64,
+    /// File names given to the object by the cached manifests
+    pub references: Vec<String>,
+}
+
+/// Reads a cached object as a manifest, if it is one.
+fn cached_manifest(cache: &download::Cache, digest: &str) -> Option<Manifest> {
+    let mut file = File::open(cache.path().join(digest)).ok()?;
+
+    // Skip reading firmware archives, which are not JSON
+    let mut data = vec![0];
+    file.read_exact(&mut data).ok()?;
+    if data[0] != b'{' {
+        return None;
+    }
+
+    file.read_to_end(&mut data).ok()?;
+    serde_json::from_slice(&data).ok()
+}
+
+/// Lists the objects in the download cache, and the names cached manifests give them.
+pub fn cache_list() -> Result<Vec<CacheEntry>, Error> {
+    let cache = download::Cache::new(config::cache(), None).map_err(Error::other)?;
+    let objects = cache.objects().map_err(Error::other)?;
+
+    let mut references = BTreeMap::<String, Vec<String>>::new();
+    for digest in objects.keys() {
+        if let Some(manifest) = cached_manifest(&cache, digest) {
+            references
+                .entry(digest.clone())
+                .or_default()
+                .push("manifest.json".to_string());
+            for (file, file_digest) in manifest.files.iter() {
+                references
+                    .entry(file_digest.clone())
+                    .or_default()
+                    .push(file.clone());
+            }
+        }
+    }
+
+    Ok(objects
+        .into_iter()
+        .map(|(digest, size)| CacheEntry {
+            references: references.remove(&digest).unwrap_or_default(),
+            digest,
+            size,
+        })
+        .collect())
+}
+
+/// Rehashes every object in the download cache, removing those that do not match their digest.
+///
+/// Returns the digests of the valid and the removed objects.
+pub fn cache_verify() -> Result<(Vec<String>, Vec<String>), Error> {
+    let cache = download::Cache::new(config::cache(), None).map_err(Error::other)?;
+
+    let mut valid = Vec::new();
+    let mut removed = Vec::new();
+    for digest in cache.objects().map_err(Error::other)?.into_keys() {
+        eprintln!("verifying {}", digest);
+        if cache.verify(&digest).map_err(Error::other)? {
+            valid.push(digest);
+        } else {
+            eprintln!("removed corrupt object {}", digest);
+            removed.push(digest);
+        }
+    }
+
+    Ok((valid, removed))
+}
+
+/// Removes objects from the download cache that are not reachable from the current tails of the
+/// firmware and Thelio Io projects, along with partial downloads that are no longer needed,
+/// returning the removed objects.
+pub fn cache_prune() -> Result<Vec<CacheEntry>, Error> {
+    let mut reachable = HashSet::new();
+    for project in [config::project(), config::thelio_io_project()] {
+        let tail_cache = crate::tail_cache_path(project, config::branch()).map_err(Error::other)?;
+
+        let result = util::RetryPolicy::default().retry(
+            || reachable_objects(&tail_cache, project),
+            || crate::remove_tail_cache(&tail_cache),
+        );
+        reachable.extend(result?);
+    }
+
+    let cache = download::Cache::new(config::cache(), None).map_err(Error::other)?;
+    let objects = cache.objects().map_err(Error::other)?;
+    let mut removed = Vec::new();
+    for (digest, size) in &objects {
+        if reachable.contains(digest) {
+            continue;
+        }
+
+        eprintln!("removing {}", digest);
+        cache.remove(digest).map_err(Error::other)?;
+        removed.push(CacheEntry {
+            digest: digest.clone(),
+            size: *size,
+            references: Vec::new(),
+        });
+    }
+
+    // Partial downloads are kept to be resumed, unless they are no longer needed
+    for (digest, size) in cache.partials().map_err(Error::other)? {
+        if reachable.contains(&digest) && !objects.contains_key(&digest) {
+            continue;
+        }
+
+        eprintln!("removing partial download of {}", digest);
+        cache.remove_partial(&digest).map_err(Error::other)?;
+        removed.push(CacheEntry {
+            digest,
+            size,
+            references: Vec::new(),
+        });
+    }
+
+    Ok(removed)
+}
+
+/// Returns the digests of the manifest of the current tail of `project` and of its files,
+/// loading the tail through `tail_cache` as downloads do.
+fn reachable_objects(tail_cache: &Path, project: &str) -> Result<Vec<String>, Failure> {
+    let trust = Trust::load()?;
+
+    eprintln!("downloading {} tail", project);
+    let fetch_tail = || trust.download_tail(project, config::branch());
+    let tail = crate::cached_tail(tail_cache, project, &trust, false, fetch_tail)
+        .map_err(Failure::from_anyhow)?;
+    let cache = download::Cache::with_sources(config::cache(), trust.sources()?)?;
+
+    let manifest_json = cache.object_named(&tail.digest, "manifest.json")?;
+    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(err_str)?;
+
+    let mut reachable = vec![tail.digest];
+    reachable.extend(manifest.files.into_values());
+    Ok(reachable)
+}
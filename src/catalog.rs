@@ -0,0 +1,211 @@
+use buildchain::Manifest;
+use serde::Serialize;
+use std::path::Path;
+
+use crate::changelog::{Changelog, Version};
+use crate::download::Failure;
+use crate::trust::Trust;
+use crate::{config, download, err_str, transition, util, Error};
+
+/// A firmware image published in the current manifest.
+#[derive(Clone, Debug, Serialize)]
+pub struct FirmwareImage {
+    pub firmware_id: String,
+    pub model: String,
+    /// SHA-256 of the EC project, as it appears in the firmware ID
+    pub project_hash: String,
+    /// Known EC projects that hash to `project_hash`, which is empty if none do
+    pub projects: Vec<String>,
+    pub digest: String,
+    /// Newest entry of the changelog of the image, if it was requested and could be read
+    pub latest: Option<Version>,
+    /// Why the changelog of the image could not be read
+    pub error: Option<String>,
+}
+
+/// Lists the firmware images in the current manifest, or only those for `model`, sorted by
+/// firmware ID.
+///
+/// With `changelog`, the archive of each listed image is downloaded to read the newest entry of
+/// its changelog. Archives that were not already cached are removed again, as `cache_prune` keeps
+/// every image in the current manifest. See `accept_tail` for `allow_downgrade`.
+pub fn firmware_list(
+    model: Option<&str>,
+    changelog: bool,
+    allow_downgrade: bool,
+) -> Result<Vec<FirmwareImage>, Error> {
+    let tail_cache =
+        crate::tail_cache_path(config::project(), config::branch()).map_err(Error::other)?;
+
+    let result = util::RetryPolicy::default().retry(
+        || firmware_list_(&tail_cache, model, changelog, allow_downgrade),
+        || crate::remove_tail_cache(&tail_cache),
+    );
+    Ok(result?)
+}
+
+fn firmware_list_(
+    tail_cache: &Path,
+    model: Option<&str>,
+    changelog: bool,
+    allow_downgrade: bool,
+) -> Result<Vec<FirmwareImage>, Failure> {
+    let trust = Trust::load()?;
+
+    eprintln!("downloading tail");
+    let fetch_tail = || trust.download_tail(config::project(), config::branch());
+    let tail = crate::cached_tail(
+        tail_cache,
+        config::project(),
+        &trust,
+        allow_downgrade,
+        fetch_tail,
+    )
+    .map_err(Failure::from_anyhow)?;
+    let cache = download::Cache::with_sources(config::cache(), trust.sources()?)?;
+
+    eprintln!("downloading manifest.json");
+    let manifest_json = cache.object_named(&tail.digest, "manifest.json")?;
+    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(err_str)?;
+
+    let mut images = Vec::new();
+    for (file, digest) in manifest.files.iter() {
+        let (image_model, project_hash) = match parse_firmware_file(file) {
+            Some(some) => some,
+            None => continue,
+        };
+        if model.is_some_and(|model| model != image_model) {
+            continue;
+        }
+
+        // One image that can not be read does not keep the others from being listed
+        let (latest, error) = if changelog {
+            match latest_version(&cache, digest, file) {
+                Ok(latest) => (latest, None),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    (None, Some(err))
+                }
+            }
+        } else {
+            (None, None)
+        };
+
+        let firmware_id = format!("{}_{}", image_model, project_hash);
+        images.push(FirmwareImage {
+            projects: matching_projects(image_model, &firmware_id),
+            firmware_id,
+            model: image_model.to_string(),
+            project_hash: project_hash.to_string(),
+            digest: digest.clone(),
+            latest,
+            error,
+        });
+    }
+
+    images.sort_by(|a, b| a.firmware_id.cmp(&b.firmware_id));
+    Ok(images)
+}
+
+/// Reads the newest entry of the changelog in a firmware archive, removing the archive from the
+/// cache afterwards if it was not there before.
+fn latest_version(
+    cache: &download::Cache,
+    digest: &str,
+    file: &str,
+) -> Result<Option<Version>, String> {
+    let cached = cache.path().join(digest).is_file();
+
+    eprintln!("downloading {}", file);
+    let archive = cache.open_named(digest, file)?;
+    let changelog = util::extract_file(archive, "./changelog.json")
+        .map_err(|err| format!("failed to read changelog of {}: {}", file, err));
+    if !cached {
+        cache.remove(digest)?;
+    }
+
+    Ok(Changelog::parse(&changelog?)?.versions.into_iter().next())
+}
+
+/// Finds the known EC projects that `firmware_id` may have been generated from.
+fn matching_projects(model: &str, firmware_id: &str) -> Vec<String> {
+    transition::known_projects()
+        .into_iter()
+        .filter(|project| crate::generate_firmware_id(model, project) == firmware_id)
+        .map(String::from)
+        .collect()
+}
+
+/// Splits a file named `<model>_<hash>.tar.xz` into the model and EC project hash.
+fn parse_firmware_file(file: &str) -> Option<(&str, &str)> {
+    let (model, project_hash) = file.strip_suffix(".tar.xz")?.rsplit_once('_')?;
+    let is_hash = project_hash.len() == 64
+        && project_hash
+            .bytes()
+            .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+    Some((model, project_hash)).filter(|_| !model.is_empty() && is_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: &str = "aeb0b2b6d1a4b2d5a1e2b5c0c8e4fd9b3a2e9c1b2fa3e4d5c6b7a8f9e0d1c2b3";
+
+    #[test]
+    fn parse_firmware_file_valid() {
+        let file = format!("galp5_{}.tar.xz", HASH);
+        assert_eq!(parse_firmware_file(&file), Some(("galp5", HASH)));
+
+        // Only the last underscore separates the hash
+        let file = format!("thelio_mira_{}.tar.xz", HASH);
+        assert_eq!(parse_firmware_file(&file), Some(("thelio_mira", HASH)));
+    }
+
+    #[test]
+    fn parse_firmware_file_invalid() {
+        for file in [
+            "system76-firmware-update.tar.xz".to_string(),
+            "manifest.json".to_string(),
+            format!("galp5_{}", HASH),
+            format!("galp5_{}.tar.gz", HASH),
+            format!("_{}.tar.xz", HASH),
+            format!("galp5_{}.tar.xz", &HASH[1..]),
+            format!("galp5_{}0.tar.xz", HASH),
+            format!("galp5_{}.tar.xz", HASH.to_uppercase()),
+            format!("galp5_{}g.tar.xz", &HASH[1..]),
+        ] {
+            assert_eq!(parse_firmware_file(&file), None, "{}", file);
+        }
+    }
+
+    #[test]
+    fn known_projects_round_trip() {
+        for project in transition::known_projects() {
+            let firmware_id = crate::generate_firmware_id("oryp5", project);
+            let file = format!("{}.tar.xz", firmware_id);
+            let (model, _) = parse_firmware_file(&file).unwrap();
+            assert_eq!(model, "oryp5");
+            assert_eq!(matching_projects(model, &firmware_id), vec![project]);
+        }
+    }
+
+    #[test]
+    fn known_projects_contents() {
+        let projects = transition::known_projects();
+        assert_eq!(&projects[..2], &["none", "76ec"]);
+        assert!(projects.contains(&"P950Ex"));
+        assert!(projects.contains(&"NH5xDC"));
+
+        let mut deduped = projects.clone();
+        deduped.sort_unstable();
+        deduped.dedup();
+        assert_eq!(deduped.len(), projects.len());
+    }
+
+    #[test]
+    fn unknown_project() {
+        let firmware_id = format!("oryp5_{}", HASH);
+        assert!(matching_projects("oryp5", &firmware_id).is_empty());
+    }
+}
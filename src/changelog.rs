@@ -0,0 +1,32 @@
+use serde::{Deserialize, Serialize};
+
+/// Changelog containing details about each version of firmware.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Changelog {
+    pub versions: Vec<Version>,
+}
+
+/// Details about a version of firmware.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Version {
+    pub date: String,
+    pub bios: String,
+    pub me: Option<String>,
+    pub description: String,
+}
+
+impl Changelog {
+    pub fn parse(json: &str) -> Result<Self, String> {
+        serde_json::from_str(json).map_err(|err| format!("failed to parse changelog: {}", err))
+    }
+
+    /// Returns the number of leading versions that are newer than `bios_version`.
+    ///
+    /// Versions are listed newest first, so everything before the entry for the installed BIOS
+    /// is newer. If the installed BIOS is not listed, `None` is returned.
+    pub fn newer_than(&self, bios_version: &str) -> Option<usize> {
+        self.versions
+            .iter()
+            .position(|version| version.bios == bios_version)
+    }
+}
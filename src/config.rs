@@ -1,15 +1,226 @@
+use serde::Deserialize;
+use std::sync::OnceLock;
+use std::{fs, io};
+
+/// Default of `Config::cache`
 pub static CACHE: &str = "/var/cache/system76-firmware-daemon";
 
 pub static KEY: &str = "4WSYXHSHEZRGI6CUEE5DS7TGGTCK2UY67OF2TW4FX2OWT2CVGWEA";
 
+/// Default of `Config::url`
 pub static URL: &str = "https://firmware.system76.com/buildchain/";
 
+/// Default of `Config::project`
 pub static PROJECT: &str = "firmware";
 
+/// Default of `Config::thelio_io_project`
 pub static THELIO_IO_PROJECT: &str = "thelio-io-firmware";
 
+/// Default of `Config::branch`
 pub static BRANCH: &str = "master";
 
+/// Default of `Config::trust_dir`
+pub static TRUST_DIR: &str = "/etc/system76-firmware/trust.d";
+
+/// Default of `Config::max_tail_age`, which disables the check, as buildchain does not re-sign
+/// tails of projects that are not published to
+pub static MAX_TAIL_AGE: u64 = 0;
+
+/// Runtime configuration, overriding the defaults above.
+pub static CONFIG_FILE: &str = "/etc/system76-firmware/config.toml";
+
+/// Environment variables override the configuration file, which may itself be moved by
+/// `SYSTEM76_FIRMWARE_CONFIG`. Trusted keys are never taken from the environment.
+static ENV_PREFIX: &str = "SYSTEM76_FIRMWARE_";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The settings that may be given in the configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    url: Option<String>,
+    branch: Option<String>,
+    cache: Option<String>,
+    project: Option<String>,
+    thelio_io_project: Option<String>,
+    max_tail_age: Option<u64>,
+    trust_dir: Option<String>,
+    keys: Vec<String>,
+}
+
+/// Configuration of the library, CLI, and daemon, loaded once per process.
+#[derive(Debug)]
+pub struct Config {
+    /// Buildchain server, or a `file://` URL or path of a local mirror laid out like one
+    pub url: String,
+    pub branch: String,
+    pub cache: String,
+    pub project: String,
+    pub thelio_io_project: String,
+    /// Tails signed longer ago than this, in seconds, are refused as possibly frozen by a mirror.
+    /// Zero disables the check. Only set this where the projects are known to be published more
+    /// often than that, as a quiet project would otherwise fail to download.
+    pub max_tail_age: u64,
+    /// Drop-in directory of additional trusted signing keys (`*.key`) and certificates (`*.pem`)
+    pub trust_dir: String,
+    /// Signing keys trusted in addition to `KEY`
+    ///
+    /// Any of these keys may sign the firmware that is installed as root, so they and
+    /// `trust_dir` are only read from `CONFIG_FILE`, which must only be writable by root.
+    pub keys: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            url: URL.to_string(),
+            branch: BRANCH.to_string(),
+            cache: CACHE.to_string(),
+            project: PROJECT.to_string(),
+            thelio_io_project: THELIO_IO_PROJECT.to_string(),
+            max_tail_age: MAX_TAIL_AGE,
+            trust_dir: TRUST_DIR.to_string(),
+            keys: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration for the rest of the process, if it has not been loaded yet.
+    ///
+    /// Programs should call this on startup, so that an invalid configuration is reported as an
+    /// error instead of being replaced by defaults, which could point at another server.
+    pub fn init() -> Result<&'static Config, String> {
+        if let Some(config) = CONFIG.get() {
+            return Ok(config);
+        }
+
+        let config = Config::load()?;
+        Ok(CONFIG.get_or_init(|| config))
+    }
+
+    /// Returns the configuration, loading it on first use.
+    ///
+    /// # Panics
+    ///
+    /// If the configuration cannot be loaded, and `init` was not called to report it.
+    pub fn get() -> &'static Config {
+        CONFIG.get_or_init(Config::initial)
+    }
+
+    #[cfg(not(test))]
+    fn initial() -> Config {
+        Config::load().unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    /// Tests use the defaults, with the cache in a temporary directory, instead of the
+    /// configuration of the machine.
+    #[cfg(test)]
+    fn initial() -> Config {
+        let cache = tempfile::tempdir().unwrap().keep();
+        Config {
+            cache: cache.to_str().unwrap().to_string(),
+            ..Config::default()
+        }
+    }
+
+    /// Loads the configuration file, if it exists, and then the environment variables.
+    ///
+    /// A configuration file moved by the environment may not add trusted keys, as anyone who can
+    /// set the environment of the CLI could then have it install their firmware.
+    pub fn load() -> Result<Config, String> {
+        let (path, trusted) = match env("CONFIG") {
+            Some(path) => (path, false),
+            None => (CONFIG_FILE.to_string(), true),
+        };
+        let file = match fs::read_to_string(&path) {
+            Ok(data) => toml::from_str::<ConfigFile>(&data)
+                .map_err(|err| format!("failed to parse {}: {}", path, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => ConfigFile::default(),
+            Err(err) => return Err(format!("failed to read {}: {}", path, err)),
+        };
+
+        if !trusted && (!file.keys.is_empty() || file.trust_dir.is_some()) {
+            return Err(format!(
+                "{} may not set keys or trust_dir, as only {} is trusted",
+                path, CONFIG_FILE
+            ));
+        }
+
+        Config::resolve(file, env)
+    }
+
+    /// Applies the settings of the configuration file, and then those of `env`.
+    fn resolve<F: Fn(&str) -> Option<String>>(file: ConfigFile, env: F) -> Result<Config, String> {
+        let mut config = Config::default();
+        let settings = [
+            (&mut config.url, file.url, "URL"),
+            (&mut config.branch, file.branch, "BRANCH"),
+            (&mut config.cache, file.cache, "CACHE"),
+            (&mut config.project, file.project, "PROJECT"),
+            (
+                &mut config.thelio_io_project,
+                file.thelio_io_project,
+                "THELIO_IO_PROJECT",
+            ),
+        ];
+        for (value, file_value, name) in settings {
+            if let Some(new_value) = env(name).or(file_value) {
+                *value = new_value;
+            }
+        }
+
+        config.max_tail_age = match env("MAX_TAIL_AGE") {
+            Some(value) => value.parse().map_err(|err| {
+                format!("invalid {}MAX_TAIL_AGE {:?}: {}", ENV_PREFIX, value, err)
+            })?,
+            None => file.max_tail_age.unwrap_or(MAX_TAIL_AGE),
+        };
+
+        if let Some(trust_dir) = file.trust_dir {
+            config.trust_dir = trust_dir;
+        }
+        config.keys = file.keys;
+
+        Ok(config)
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(format!("{}{}", ENV_PREFIX, name))
+        .ok()
+        .filter(|value| !value.is_empty())
+}
+
+pub fn url() -> &'static str {
+    &Config::get().url
+}
+
+pub fn branch() -> &'static str {
+    &Config::get().branch
+}
+
+pub fn cache() -> &'static str {
+    &Config::get().cache
+}
+
+pub fn project() -> &'static str {
+    &Config::get().project
+}
+
+pub fn thelio_io_project() -> &'static str {
+    &Config::get().thelio_io_project
+}
+
+pub fn max_tail_age() -> u64 {
+    Config::get().max_tail_age
+}
+
+pub fn trust_dir() -> &'static str {
+    &Config::get().trust_dir
+}
+
 pub static CERT: &[u8] = br#"
 -----BEGIN CERTIFICATE-----
 MIIFeTCCA2GgAwIBAgIJAOM3Go178VbKMA0GCSqGSIb3DQEBDAUAMFMxCzAJBgNV
@@ -44,3 +255,96 @@ tTJeO0RLsSU2A6oirLPl/Eyym7f3X8J6xxsmRABMiZdWY/fpY+eZrZJcutrLeTJz
 7WGQ/z3ABIMFGxr+pw==
 -----END CERTIFICATE-----
 "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(data: &str) -> ConfigFile {
+        toml::from_str(data).unwrap()
+    }
+
+    fn no_env(_name: &str) -> Option<String> {
+        None
+    }
+
+    #[test]
+    fn resolve_defaults() {
+        let config = Config::resolve(ConfigFile::default(), no_env).unwrap();
+        assert_eq!(config.url, URL);
+        assert_eq!(config.branch, BRANCH);
+        assert_eq!(config.max_tail_age, MAX_TAIL_AGE);
+        assert_eq!(config.trust_dir, TRUST_DIR);
+        assert!(config.keys.is_empty());
+    }
+
+    #[test]
+    fn resolve_file() {
+        let file = parse(
+            r#"
+            url = "https://staging.example.com/buildchain/"
+            branch = "beta"
+            cache = "/tmp/cache"
+            project = "firmware-beta"
+            thelio_io_project = "thelio-io-beta"
+            max_tail_age = 3600
+            trust_dir = "/tmp/trust.d"
+            keys = ["KEY"]
+            "#,
+        );
+
+        let config = Config::resolve(file, no_env).unwrap();
+        assert_eq!(config.url, "https://staging.example.com/buildchain/");
+        assert_eq!(config.branch, "beta");
+        assert_eq!(config.cache, "/tmp/cache");
+        assert_eq!(config.project, "firmware-beta");
+        assert_eq!(config.thelio_io_project, "thelio-io-beta");
+        assert_eq!(config.max_tail_age, 3600);
+        assert_eq!(config.trust_dir, "/tmp/trust.d");
+        assert_eq!(config.keys, ["KEY"]);
+    }
+
+    #[test]
+    fn parse_unknown_field() {
+        assert!(toml::from_str::<ConfigFile>("urls = \"x\"").is_err());
+    }
+
+    #[test]
+    fn env_overrides_file() {
+        let file = parse("url = \"file\"\nbranch = \"beta\"\nmax_tail_age = 3600");
+        let config = Config::resolve(file, |name| match name {
+            "URL" => Some("env".to_string()),
+            "MAX_TAIL_AGE" => Some("60".to_string()),
+            _ => None,
+        })
+        .unwrap();
+
+        assert_eq!(config.url, "env");
+        assert_eq!(config.branch, "beta");
+        assert_eq!(config.max_tail_age, 60);
+    }
+
+    #[test]
+    fn env_invalid_max_tail_age() {
+        let env = |name: &str| {
+            Some("a year")
+                .filter(|_| name == "MAX_TAIL_AGE")
+                .map(String::from)
+        };
+        assert!(Config::resolve(ConfigFile::default(), env).is_err());
+    }
+
+    #[test]
+    fn env_cannot_add_trust() {
+        let file = parse("keys = [\"KEY\"]");
+        let config = Config::resolve(file, |name| match name {
+            "KEYS" => Some("OTHER".to_string()),
+            "TRUST_DIR" => Some("/tmp".to_string()),
+            _ => None,
+        })
+        .unwrap();
+
+        assert_eq!(config.keys, ["KEY"]);
+        assert_eq!(config.trust_dir, TRUST_DIR);
+    }
+}
@@ -1,55 +1,892 @@
 use buildchain::{Downloader, Sha384};
-use std::fs::{self, File};
-use std::io::{Read, Write};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use std::{error, fmt};
 
-use crate::err_str;
+use crate::{config, err_str, util};
+
+/// Mode of the cache directory and the directories in it, which only root may access.
+const DIR_MODE: u32 = 0o700;
+
+/// Mode of the files in the cache.
+const FILE_MODE: u32 = 0o600;
+
+/// Creates a directory in the cache, along with any missing parents, accessible only by root.
+pub(crate) fn create_dir<P: AsRef<Path>>(path: P) -> Result<(), String> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(DIR_MODE)
+        .create(path.as_ref())
+        .map_err(|err| format!("failed to create {}: {}", path.as_ref().display(), err))
+}
+
+/// Atomically writes a file in the cache, readable only by root.
+pub(crate) fn write<P: AsRef<Path>>(path: P, data: &[u8]) -> Result<(), String> {
+    util::write_atomic(path.as_ref(), data, FILE_MODE)
+        .map_err(|err| format!("failed to write {}: {}", path.as_ref().display(), err))
+}
+
+/// Corrects the modes of the cache and everything in it, which may have been created with the
+/// defaults by older versions. This walks the whole cache, so it is only run on startup.
+pub fn secure_cache() -> Result<(), String> {
+    let path = Path::new(config::cache());
+    if !path.is_dir() {
+        return Ok(());
+    }
+    secure(path).map_err(|err| format!("failed to secure {}: {}", path.display(), err))
+}
+
+fn secure(dir: &Path) -> io::Result<()> {
+    set_mode(dir, DIR_MODE)?;
+    for entry_res in fs::read_dir(dir)? {
+        let entry = entry_res?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            secure(&entry.path())?;
+        } else if file_type.is_file() {
+            set_mode(&entry.path(), FILE_MODE)?;
+        }
+    }
+    Ok(())
+}
+
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let permissions = fs::metadata(path)?.permissions();
+    if permissions.mode() & 0o7777 != mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// The class of a download failure, which decides whether retrying it can help.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureKind {
+    /// The network or server failed in a way that may pass, like a timeout or a 503
+    Transient,
+    /// The server does not have the requested tail or object
+    NotFound,
+    /// A signature, digest, or rollback check failed
+    Verification,
+    /// Any other failure, which retrying will not fix
+    Other,
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FailureKind::Transient => "transient network failure",
+            FailureKind::NotFound => "not found",
+            FailureKind::Verification => "verification failure",
+            FailureKind::Other => "failure",
+        })
+    }
+}
+
+/// A failure to download or verify firmware, classified by its `FailureKind`.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub kind: FailureKind,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new<S: Into<String>>(kind: FailureKind, message: S) -> Self {
+        Failure {
+            kind,
+            message: message.into(),
+        }
+    }
+
+    /// Classifies a failed request for `url` by its status, or by how the connection failed.
+    pub(crate) fn request(url: &str, err: reqwest::Error) -> Self {
+        use reqwest::StatusCode;
+
+        let kind = match err.status() {
+            Some(StatusCode::NOT_FOUND | StatusCode::GONE) => FailureKind::NotFound,
+            Some(StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS) => {
+                FailureKind::Transient
+            }
+            Some(status) if status.is_server_error() => FailureKind::Transient,
+            Some(_) => FailureKind::Other,
+            None if err.is_builder() => FailureKind::Other,
+            None => FailureKind::Transient,
+        };
+        Failure::new(kind, format!("failed to download {}: {}", url, err))
+    }
+
+    /// Finds the failure in the chain of an error built with `anyhow`, keeping the messages of
+    /// the whole chain.
+    pub(crate) fn from_anyhow(err: anyhow::Error) -> Self {
+        let kind = err
+            .downcast_ref::<Failure>()
+            .map_or(FailureKind::Other, |failure| failure.kind);
+        Failure::new(kind, format!("{:#}", err))
+    }
+
+    /// Combines the failures of several sources, which is transient if any of them was, so that
+    /// it is retried.
+    fn join(failures: Vec<Failure>) -> Self {
+        let kind = if failures
+            .iter()
+            .any(|failure| failure.kind == FailureKind::Transient)
+        {
+            FailureKind::Transient
+        } else {
+            failures
+                .first()
+                .map_or(FailureKind::Other, |failure| failure.kind)
+        };
+        let messages = failures
+            .into_iter()
+            .map(|failure| failure.message)
+            .collect::<Vec<_>>();
+        Failure::new(kind, messages.join(", "))
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl error::Error for Failure {}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::new(FailureKind::Other, message)
+    }
+}
+
+impl From<Failure> for String {
+    fn from(failure: Failure) -> Self {
+        failure.message
+    }
+}
+
+/// How far the download of an object has come, as passed to the callback of `set_progress`.
+#[derive(Clone, Copy, Debug)]
+pub struct Progress<'a> {
+    /// File name of the object in the manifest, or its digest if the name is not known
+    pub name: &'a str,
+    pub received: u64,
+    /// Size of the object, if the source reported it. It is always set in the last report, once
+    /// the download completes.
+    pub total: Option<u64>,
+}
+
+type ProgressCallback = Box<dyn Fn(&Progress) + Send + Sync>;
+
+static PROGRESS: RwLock<Option<ProgressCallback>> = RwLock::new(None);
+
+/// Least time between reports of the same download, apart from the first and last.
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Sets the function called as objects are downloaded, replacing any set before. It is called
+/// once when a download starts, periodically while it runs, and once when it completes.
+pub fn set_progress<F: Fn(&Progress) + Send + Sync + 'static>(callback: F) {
+    *PROGRESS.write().unwrap_or_else(|err| err.into_inner()) = Some(Box::new(callback));
+}
+
+fn report(name: &str, received: u64, total: Option<u64>) {
+    let callback = PROGRESS.read().unwrap_or_else(|err| err.into_inner());
+    if let Some(callback) = callback.as_ref() {
+        callback(&Progress {
+            name,
+            received,
+            total,
+        });
+    }
+}
+
+/// Copies `reader` to `writer`, reporting progress as it goes, counting from `received` bytes
+/// that were already downloaded.
+fn copy_progress<R: Read, W: Write>(
+    mut reader: R,
+    writer: &mut W,
+    name: &str,
+    mut received: u64,
+    total: Option<u64>,
+) -> io::Result<u64> {
+    let mut buf = vec![0; 64 * 1024];
+    let mut reported = Instant::now();
+    report(name, received, total);
+    loop {
+        let count = match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(count) => count,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        };
+        writer.write_all(&buf[..count])?;
+        received += count as u64;
+
+        if reported.elapsed() >= PROGRESS_INTERVAL {
+            report(name, received, total);
+            reported = Instant::now();
+        }
+    }
+    report(name, received, Some(received));
+    Ok(received)
+}
+
+/// Where objects missing from a `Cache` are fetched from.
+pub enum Source {
+    Remote(Downloader),
+    /// A buildchain server, whose objects are streamed with `client` so progress can be reported
+    Http {
+        url: String,
+        client: reqwest::blocking::Client,
+    },
+    /// A directory laid out like the buildchain server, such as a mirror on an NFS share
+    Local(PathBuf),
+}
+
+impl Source {
+    pub fn object(&self, digest: &str) -> Result<Vec<u8>, Failure> {
+        let mut file = tempfile::tempfile().map_err(err_str)?;
+        self.fetch(digest, digest, &mut file)?;
+
+        let mut data = Vec::new();
+        file.rewind().map_err(err_str)?;
+        file.read_to_end(&mut data).map_err(err_str)?;
+
+        let sha = Sha384::new(data.as_slice()).map_err(err_str)?;
+        if sha.to_base32() != digest {
+            return Err(Failure::new(
+                FailureKind::Verification,
+                format!("object does not match digest: {}", digest),
+            ));
+        }
+
+        Ok(data)
+    }
+
+    /// Writes an object to `file`, reporting progress under `name`. If `file` is not empty, the
+    /// download resumes after its contents where the source allows it, and starts over where it
+    /// does not. The data is not checked against the digest, which is left to the caller.
+    ///
+    /// Returns the offset the download resumed from, which is zero if it started over.
+    pub fn fetch(&self, digest: &str, name: &str, file: &mut File) -> Result<u64, Failure> {
+        let offset = file.seek(SeekFrom::End(0)).map_err(err_str)?;
+        match self {
+            Source::Remote(downloader) => {
+                let data = downloader.object(digest)?;
+                restart(file)?;
+                report(name, data.len() as u64, Some(data.len() as u64));
+                file.write_all(&data).map_err(err_str)?;
+                Ok(0)
+            }
+            Source::Http { url, client } => {
+                let url = format!("{}/object/{}", url.trim_end_matches('/'), digest);
+                let mut request = client.get(&url);
+                if offset > 0 {
+                    request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+                }
+                let response = request.send().map_err(|err| Failure::request(&url, err))?;
+
+                let (offset, total, response) = match response.status() {
+                    reqwest::StatusCode::PARTIAL_CONTENT => {
+                        // A range other than the one requested can not be appended, so it is
+                        // downloaded from the start when retried
+                        let start = response
+                            .headers()
+                            .get(reqwest::header::CONTENT_RANGE)
+                            .and_then(|value| value.to_str().ok())
+                            .and_then(content_range_start);
+                        if start != Some(offset) {
+                            restart(file)?;
+                            return Err(Failure::new(
+                                FailureKind::Transient,
+                                format!("{} returned a range not starting at {}", url, offset),
+                            ));
+                        }
+
+                        let total = response.content_length().map(|length| offset + length);
+                        (offset, total, response)
+                    }
+                    // The partial file is already complete, or too long to be the object, which
+                    // the digest check will show
+                    reqwest::StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(offset),
+                    _ => {
+                        let response = response
+                            .error_for_status()
+                            .map_err(|err| Failure::request(&url, err))?;
+                        restart(file)?;
+                        let total = response.content_length();
+                        (0, total, response)
+                    }
+                };
+
+                // The kept partial file lets a dropped connection be resumed when retried
+                copy_progress(response, file, name, offset, total)
+                    .map(|_| offset)
+                    .map_err(|err| {
+                        Failure::new(
+                            FailureKind::Transient,
+                            format!("failed to download {}: {}", url, err),
+                        )
+                    })
+            }
+            Source::Local(dir) => {
+                let path = dir.join("object").join(digest);
+                let mut source = File::open(&path).map_err(|err| {
+                    let kind = if err.kind() == io::ErrorKind::NotFound {
+                        FailureKind::NotFound
+                    } else {
+                        FailureKind::Other
+                    };
+                    Failure::new(kind, format!("failed to read {}: {}", path.display(), err))
+                })?;
+                let total = source.metadata().map_err(err_str)?.len();
+
+                let offset = if offset <= total {
+                    source.seek(SeekFrom::Start(offset)).map_err(err_str)?
+                } else {
+                    restart(file)?;
+                    0
+                };
+
+                copy_progress(source, file, name, offset, Some(total))
+                    .map(|_| offset)
+                    .map_err(|err| format!("failed to read {}: {}", path.display(), err).into())
+            }
+        }
+    }
+}
+
+/// Parses the first byte of a `Content-Range` header, such as `bytes 100-199/200`.
+fn content_range_start(value: &str) -> Option<u64> {
+    let (start, _) = value.strip_prefix("bytes ")?.split_once('-')?;
+    start.parse().ok()
+}
+
+/// Discards what was written to a download that cannot be resumed.
+fn restart(file: &mut File) -> Result<(), String> {
+    file.set_len(0).map_err(err_str)?;
+    file.rewind().map(|_| ()).map_err(err_str)
+}
+
+/// Returns the directory of a local mirror, if `url` is a `file://` URL or an absolute path.
+pub fn local_mirror(url: &str) -> Option<PathBuf> {
+    if let Some(path) = url.strip_prefix("file://") {
+        Some(PathBuf::from(path))
+    } else if url.starts_with('/') {
+        Some(PathBuf::from(url))
+    } else {
+        None
+    }
+}
 
 pub struct Cache {
     path: PathBuf,
-    downloader: Option<Downloader>,
+    sources: Vec<Source>,
 }
 
 impl Cache {
     pub fn new<P: AsRef<Path>>(path: P, downloader: Option<Downloader>) -> Result<Cache, String> {
+        Self::with_sources(path, downloader.into_iter().map(Source::Remote).collect())
+    }
+
+    /// Creates a cache that fetches missing objects from the first of `sources` to succeed.
+    pub fn with_sources<P: AsRef<Path>>(path: P, sources: Vec<Source>) -> Result<Cache, String> {
         if !path.as_ref().is_dir() {
-            fs::create_dir(path.as_ref()).map_err(err_str)?;
+            create_dir(path.as_ref())?;
         }
 
         Ok(Cache {
             path: path.as_ref().to_owned(),
-            downloader,
+            sources,
         })
     }
 
-    pub fn object(&self, digest: &str) -> Result<Vec<u8>, String> {
-        //TODO: Atomic, with permissions
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Lists the digests and sizes of the objects in the cache.
+    pub fn objects(&self) -> Result<BTreeMap<String, u64>, String> {
+        let mut objects = BTreeMap::new();
+        for entry_res in fs::read_dir(&self.path).map_err(err_str)? {
+            let entry = entry_res.map_err(err_str)?;
+            let metadata = entry.metadata().map_err(err_str)?;
+            if !metadata.is_file() {
+                continue;
+            }
 
-        let path = self.path.join(digest);
-        if path.is_file() {
-            let mut data = Vec::new();
-            {
-                let mut file = File::open(&path).map_err(err_str)?;
-                file.read_to_end(&mut data).map_err(err_str)?;
+            // Other files, like the tail history, are not named by a base32 digest
+            if let Some(digest) = entry.file_name().to_str().filter(|name| is_digest(name)) {
+                objects.insert(digest.to_string(), metadata.len());
             }
+        }
+        Ok(objects)
+    }
+
+    /// Checks that a cached object matches its digest, removing it if it does not.
+    pub fn verify(&self, digest: &str) -> Result<bool, String> {
+        let path = self.path.join(digest);
+        let file = File::open(&path).map_err(err_str)?;
+        let sha = Sha384::new(file).map_err(err_str)?;
+        if sha.to_base32() == digest {
+            Ok(true)
+        } else {
+            fs::remove_file(&path).map_err(err_str)?;
+            Ok(false)
+        }
+    }
+
+    pub fn remove(&self, digest: &str) -> Result<(), String> {
+        fs::remove_file(self.path.join(digest)).map_err(err_str)
+    }
+
+    /// Streams an object obtained elsewhere into the cache directory, checking that it matches the
+    /// digest as it is written. It is only used as a cached object once it is committed.
+    pub(crate) fn stage<R: Read>(&self, digest: &str, reader: R) -> Result<Staged, String> {
+        // The digest names the file, so it must not be able to leave the cache directory
+        if !is_digest(digest) {
+            return Err(format!("invalid digest: {}", digest));
+        }
+
+        let mut file = tempfile::Builder::new()
+            .prefix(&format!("{}.", digest))
+            .suffix(".partial")
+            .tempfile_in(&self.path)
+            .map_err(err_str)?;
+        let sha = Sha384::new(Tee {
+            reader,
+            writer: file.as_file_mut(),
+        })
+        .map_err(err_str)?;
+        if sha.to_base32() != digest {
+            return Err(format!("object does not match digest: {}", digest));
+        }
+        file.as_file().sync_all().map_err(err_str)?;
+
+        Ok(Staged {
+            file,
+            path: self.path.join(digest),
+        })
+    }
 
-            let sha = Sha384::new(data.as_slice()).map_err(err_str)?;
+    pub fn object(&self, digest: &str) -> Result<Vec<u8>, Failure> {
+        self.object_named(digest, digest)
+    }
+
+    /// Like `object`, reporting the progress of a download under `name`.
+    pub fn object_named(&self, digest: &str, name: &str) -> Result<Vec<u8>, Failure> {
+        let mut data = Vec::new();
+        self.open_named(digest, name)?
+            .read_to_end(&mut data)
+            .map_err(err_str)?;
+        Ok(data)
+    }
+
+    /// Opens an object, fetching it if it is not cached. The file is hashed while it is read from
+    /// disk, so large objects can be streamed without holding them in memory.
+    pub fn open(&self, digest: &str) -> Result<File, Failure> {
+        self.open_named(digest, digest)
+    }
+
+    /// Like `open`, reporting the progress of a download under `name`.
+    pub fn open_named(&self, digest: &str, name: &str) -> Result<File, Failure> {
+        let path = self.path.join(digest);
+        if path.is_file() {
+            let mut file = File::open(&path).map_err(err_str)?;
+            let sha = Sha384::new(&mut file).map_err(err_str)?;
             if sha.to_base32() == digest {
-                return Ok(data);
+                file.rewind().map_err(err_str)?;
+                return Ok(file);
             } else {
                 fs::remove_file(&path).map_err(err_str)?;
             }
         }
 
-        if let Some(ref downloader) = self.downloader {
-            let data = downloader.object(digest)?;
-            {
-                let mut file = File::create(&path).map_err(err_str)?;
-                file.write_all(&data).map_err(err_str)?;
+        if self.sources.is_empty() {
+            return Err(Failure::new(
+                FailureKind::NotFound,
+                format!("could not find digest in cache: {}", digest),
+            ));
+        }
+
+        let mut failures = Vec::new();
+        for source in &self.sources {
+            match self.fetch(source, digest, name) {
+                Ok(file) => return Ok(file),
+                Err(failure) => failures.push(failure),
             }
-            Ok(data)
-        } else {
-            Err(format!("could not find digest in cache: {}", digest))
         }
+        Err(Failure::join(failures))
+    }
+
+    /// Lists the digests and sizes of partially downloaded objects.
+    pub fn partials(&self) -> Result<BTreeMap<String, u64>, String> {
+        let mut partials = BTreeMap::new();
+        for entry_res in fs::read_dir(&self.path).map_err(err_str)? {
+            let entry = entry_res.map_err(err_str)?;
+            let name = entry.file_name();
+            let digest = name
+                .to_str()
+                .and_then(|name| name.strip_suffix(".partial"))
+                .filter(|digest| is_digest(digest));
+            if let Some(digest) = digest {
+                partials.insert(digest.to_string(), entry.metadata().map_err(err_str)?.len());
+            }
+        }
+        Ok(partials)
+    }
+
+    pub fn remove_partial(&self, digest: &str) -> Result<(), String> {
+        fs::remove_file(self.partial_path(digest)).map_err(err_str)
+    }
+
+    fn partial_path(&self, digest: &str) -> PathBuf {
+        self.path.join(format!("{}.partial", digest))
+    }
+
+    /// Downloads an object from `source`, resuming its partial file if an earlier attempt was
+    /// interrupted. The partial file is moved into place once it matches the digest, and removed
+    /// if it does not.
+    fn fetch(&self, source: &Source, digest: &str, name: &str) -> Result<File, Failure> {
+        let path = self.path.join(digest);
+        if path.is_file() {
+            return File::open(&path).map_err(|err| err_str(err).into());
+        }
+
+        let partial_path = self.partial_path(digest);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(FILE_MODE)
+            .open(&partial_path)
+            .map_err(|err| format!("failed to open {}: {}", partial_path.display(), err))?;
+
+        // Another process, like the daemon, may be downloading the same object
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } < 0 {
+            return Err(format!(
+                "failed to lock {}: {}",
+                partial_path.display(),
+                io::Error::last_os_error()
+            )
+            .into());
+        }
+        if path.is_file() {
+            // The partial file was created again by opening it, after the other process moved
+            // its own into place
+            if file.metadata().is_ok_and(|metadata| metadata.len() == 0) {
+                let _ = fs::remove_file(&partial_path);
+            }
+            return File::open(&path).map_err(|err| err_str(err).into());
+        }
+
+        let offset = source.fetch(digest, name, &mut file)?;
+
+        file.sync_all().map_err(err_str)?;
+        file.rewind().map_err(err_str)?;
+        let sha = Sha384::new(&mut file).map_err(err_str)?;
+        if sha.to_base32() != digest {
+            fs::remove_file(&partial_path).map_err(err_str)?;
+            // The part kept from an earlier attempt may be what was corrupted, so an object that
+            // was resumed is retried from the start before it is treated as a bad object
+            if offset > 0 {
+                return Err(Failure::new(
+                    FailureKind::Transient,
+                    format!("resumed object does not match digest: {}", digest),
+                ));
+            }
+            return Err(Failure::new(
+                FailureKind::Verification,
+                format!("object does not match digest: {}", digest),
+            ));
+        }
+        file.rewind().map_err(err_str)?;
+
+        fs::rename(&partial_path, &path).map_err(err_str)?;
+        util::sync_dir(&self.path).map_err(err_str)?;
+        Ok(file)
+    }
+}
+
+/// An object written to the cache directory under a temporary name by `Cache::stage`, which is
+/// removed if it is dropped before it is committed.
+pub(crate) struct Staged {
+    file: tempfile::NamedTempFile,
+    path: PathBuf,
+}
+
+impl Staged {
+    /// Moves the object into place in the cache.
+    pub(crate) fn commit(self) -> Result<(), String> {
+        let dir = self.path.parent().map(Path::to_owned);
+        self.file
+            .persist(&self.path)
+            .map_err(|err| format!("failed to write {}: {}", self.path.display(), err.error))?;
+        match dir {
+            Some(dir) => util::sync_dir(dir).map_err(err_str),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes everything read from `reader` to `writer`, so that it can be hashed while it is copied.
+struct Tee<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.reader.read(buf)?;
+        self.writer.write_all(&buf[..count])?;
+        Ok(count)
+    }
+}
+
+pub(crate) fn is_digest(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_uppercase() || (b'2'..=b'7').contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_failure(status: u16) -> Failure {
+        let response = http::Response::builder().status(status).body("").unwrap();
+        let err = reqwest::blocking::Response::from(response)
+            .error_for_status()
+            .unwrap_err();
+        Failure::request("https://example.com", err)
+    }
+
+    #[test]
+    fn request_not_found() {
+        assert_eq!(status_failure(404).kind, FailureKind::NotFound);
+        assert_eq!(status_failure(410).kind, FailureKind::NotFound);
+    }
+
+    #[test]
+    fn request_transient() {
+        for status in [408, 429, 500, 502, 503, 504] {
+            assert_eq!(
+                status_failure(status).kind,
+                FailureKind::Transient,
+                "{}",
+                status
+            );
+        }
+
+        // Nothing listens on the discard port of the loopback address
+        let err = reqwest::blocking::get("http://127.0.0.1:9/").unwrap_err();
+        assert_eq!(
+            Failure::request("http://127.0.0.1:9/", err).kind,
+            FailureKind::Transient
+        );
+    }
+
+    #[test]
+    fn request_other() {
+        assert_eq!(status_failure(400).kind, FailureKind::Other);
+        assert_eq!(status_failure(403).kind, FailureKind::Other);
+
+        let err = reqwest::blocking::get("not a url").unwrap_err();
+        assert!(err.is_builder());
+        assert_eq!(Failure::request("not a url", err).kind, FailureKind::Other);
+    }
+
+    #[test]
+    fn join() {
+        let failure = |kind| Failure::new(kind, format!("{:?}", kind));
+
+        let joined = Failure::join(vec![
+            failure(FailureKind::NotFound),
+            failure(FailureKind::Transient),
+        ]);
+        assert_eq!(joined.kind, FailureKind::Transient);
+        assert_eq!(joined.message, "NotFound, Transient");
+
+        let joined = Failure::join(vec![
+            failure(FailureKind::Verification),
+            failure(FailureKind::NotFound),
+        ]);
+        assert_eq!(joined.kind, FailureKind::Verification);
+
+        assert_eq!(Failure::join(Vec::new()).kind, FailureKind::Other);
+    }
+
+    #[test]
+    fn from_anyhow() {
+        use anyhow::Context;
+
+        let err = Err::<(), _>(Failure::new(FailureKind::Transient, "timed out"))
+            .context("failed to fetch tail")
+            .unwrap_err();
+        let failure = Failure::from_anyhow(err);
+        assert_eq!(failure.kind, FailureKind::Transient);
+        assert_eq!(failure.message, "failed to fetch tail: timed out");
+
+        let failure = Failure::from_anyhow(anyhow::anyhow!("timestamp exceeded"));
+        assert_eq!(failure.kind, FailureKind::Other);
+    }
+
+    fn digest(data: &[u8]) -> String {
+        Sha384::new(data).unwrap().to_base32()
+    }
+
+    /// Lists the files in `dir`, sorted by name.
+    fn files(dir: &Path) -> Vec<String> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn stage_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path(), None).unwrap();
+        let data = b"firmware";
+
+        let staged = cache.stage(&digest(data), &data[..]).unwrap();
+        assert!(cache.objects().unwrap().is_empty());
+
+        staged.commit().unwrap();
+        assert_eq!(files(dir.path()), vec![digest(data)]);
+        assert_eq!(cache.object(&digest(data)).unwrap(), data);
+    }
+
+    #[test]
+    fn stage_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path(), None).unwrap();
+
+        assert!(cache.stage(&digest(b"firmware"), &b"tampered"[..]).is_err());
+        assert!(files(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn stage_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path(), None).unwrap();
+
+        drop(cache.stage(&digest(b"firmware"), &b"firmware"[..]).unwrap());
+        assert!(files(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn stage_invalid_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Cache::new(dir.path().join("cache"), None).unwrap();
+
+        assert!(cache.stage("../escape", &b""[..]).is_err());
+        assert_eq!(files(dir.path()), vec!["cache"]);
+    }
+
+    #[test]
+    fn content_range() {
+        assert_eq!(content_range_start("bytes 100-199/200"), Some(100));
+        assert_eq!(content_range_start("bytes 0-199/*"), Some(0));
+        assert_eq!(content_range_start("bytes */200"), None);
+        assert_eq!(content_range_start("items 100-199/200"), None);
+        assert_eq!(content_range_start(""), None);
+    }
+
+    #[test]
+    fn local_mirror_url() {
+        assert_eq!(
+            local_mirror("file:///srv/mirror"),
+            Some(PathBuf::from("/srv/mirror"))
+        );
+        assert_eq!(
+            local_mirror("/srv/mirror"),
+            Some(PathBuf::from("/srv/mirror"))
+        );
+        assert_eq!(local_mirror("https://example.com/buildchain/"), None);
+    }
+
+    /// Creates a local mirror with `objects`, and a cache fetching from it.
+    fn local_cache(objects: &[&[u8]]) -> (tempfile::TempDir, tempfile::TempDir, Cache) {
+        let mirror = tempfile::tempdir().unwrap();
+        let object_dir = mirror.path().join("object");
+        fs::create_dir(&object_dir).unwrap();
+        for data in objects {
+            fs::write(object_dir.join(digest(data)), data).unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let sources = vec![Source::Local(mirror.path().to_path_buf())];
+        let cache = Cache::with_sources(dir.path(), sources).unwrap();
+        (mirror, dir, cache)
+    }
+
+    #[test]
+    fn fetch_local() {
+        let data = b"firmware";
+        let (_mirror, dir, cache) = local_cache(&[data]);
+
+        assert_eq!(cache.object(&digest(data)).unwrap(), data);
+        assert_eq!(files(dir.path()), vec![digest(data)]);
+
+        let failure = cache.object(&digest(b"missing")).unwrap_err();
+        assert_eq!(failure.kind, FailureKind::NotFound);
+    }
+
+    #[test]
+    fn fetch_resume() {
+        let data = b"firmware";
+        let (_mirror, dir, cache) = local_cache(&[data]);
+
+        fs::write(cache.partial_path(&digest(data)), &data[..4]).unwrap();
+        assert_eq!(cache.object(&digest(data)).unwrap(), data);
+        assert_eq!(files(dir.path()), vec![digest(data)]);
+    }
+
+    #[test]
+    fn fetch_resume_corrupt() {
+        let data = b"firmware";
+        let (_mirror, dir, cache) = local_cache(&[data]);
+
+        // The corrupt part is kept by the resume, so the retry starts over
+        fs::write(cache.partial_path(&digest(data)), b"FIRM").unwrap();
+        let failure = cache.object(&digest(data)).unwrap_err();
+        assert_eq!(failure.kind, FailureKind::Transient);
+        assert!(files(dir.path()).is_empty());
+
+        assert_eq!(cache.object(&digest(data)).unwrap(), data);
+    }
+
+    #[test]
+    fn fetch_restart() {
+        let data = b"firmware";
+        let (_mirror, dir, cache) = local_cache(&[data]);
+
+        // Longer than the object, so it can not be resumed
+        fs::write(cache.partial_path(&digest(data)), b"firmware and more").unwrap();
+        assert_eq!(cache.object(&digest(data)).unwrap(), data);
+        assert_eq!(files(dir.path()), vec![digest(data)]);
+    }
+
+    #[test]
+    fn fetch_mismatch() {
+        let data = b"firmware";
+        let (mirror, dir, cache) = local_cache(&[data]);
+        fs::write(mirror.path().join("object").join(digest(data)), b"tampered").unwrap();
+
+        let failure = cache.object(&digest(data)).unwrap_err();
+        assert_eq!(failure.kind, FailureKind::Verification);
+        assert!(files(dir.path()).is_empty());
     }
 }
@@ -7,14 +7,18 @@ use std::process::Command;
 use std::str;
 use std::time::Duration;
 
-use crate::{err_str, util};
+use crate::{err_str, util, Error};
 
 // Helper function for errors
 pub fn ectool_err<E: ::std::fmt::Debug>(err: E) -> String {
     format!("{:?}", err)
 }
 
-pub fn ec(primary: bool) -> Result<(String, String), String> {
+pub fn ec(primary: bool) -> Result<(String, String), Error> {
+    ec_(primary).map_err(|err| Error::hardware("failed to read EC", err))
+}
+
+fn ec_(primary: bool) -> Result<(String, String), String> {
     let sys_vendor = match util::read_string("/sys/class/dmi/id/sys_vendor") {
         Ok(ok) => ok.trim().to_string(),
         Err(err) => {
@@ -0,0 +1,93 @@
+use std::{error, io};
+
+use crate::download::Failure;
+
+/// The error underlying an `Error`. Parts of this crate only report a message, which is kept in
+/// place of the error it describes.
+type Source = Box<dyn error::Error + Send + Sync>;
+
+/// An error of the public functions of this crate, classed by what failed so that the daemon and
+/// CLI can tell callers which class of failure occurred.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The product is not one that System76 firmware is published for
+    #[error("product is not in whitelist")]
+    NotWhitelisted,
+    /// The BIOS, EC, or ME could not be read, or no firmware ID could be derived from them
+    #[error("{context}: {source}")]
+    Hardware {
+        context: String,
+        #[source]
+        source: Source,
+    },
+    /// A tail or object could not be downloaded or verified, classed by its `FailureKind`
+    #[error(transparent)]
+    Download(#[from] Failure),
+    /// Updates can only be scheduled when booted with UEFI
+    #[error("must be run using UEFI boot")]
+    NotUefi,
+    /// The EFI boot variables could not be changed with `efibootmgr`
+    #[error("{context}: {source}")]
+    EfiVars {
+        context: String,
+        #[source]
+        source: Source,
+    },
+    /// The updater could not be written to or removed from the ESP
+    #[error("{context}: {source}")]
+    Esp {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// A Thelio Io device could not be found, switched to its bootloader, or flashed
+    #[error("{context}: {source}")]
+    ThelioIo {
+        context: String,
+        #[source]
+        source: Source,
+    },
+    /// Any other failure, such as of the download cache
+    #[error(transparent)]
+    Other(Source),
+}
+
+impl Error {
+    pub(crate) fn hardware<S: Into<String>, E: Into<Source>>(context: S, source: E) -> Self {
+        Error::Hardware {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn efi_vars<S: Into<String>, E: Into<Source>>(context: S, source: E) -> Self {
+        Error::EfiVars {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn esp<S: Into<String>>(context: S, source: io::Error) -> Self {
+        Error::Esp {
+            context: context.into(),
+            source,
+        }
+    }
+
+    pub(crate) fn thelio_io<S: Into<String>, E: Into<Source>>(context: S, source: E) -> Self {
+        Error::ThelioIo {
+            context: context.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn other<E: Into<Source>>(source: E) -> Self {
+        Error::Other(source.into())
+    }
+}
+
+impl From<Error> for String {
+    fn from(err: Error) -> Self {
+        err.to_string()
+    }
+}
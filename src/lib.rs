@@ -2,28 +2,49 @@
 extern crate anyhow;
 
 use anyhow::Context;
-use buildchain::{Block, Downloader, Manifest};
+use buildchain::Manifest;
+use serde::Serialize;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use crate::download::{Failure, FailureKind};
+use crate::tail::{history_key, SignedTail, Tail, TailHistory};
+use crate::trust::Trust;
+
+pub mod changelog;
 pub mod config;
 pub mod download;
 pub mod util;
 
 mod bios;
 mod boot;
+mod bundle;
+mod cache;
+mod catalog;
 mod ec;
+mod error;
 mod me;
+mod mirror;
 mod mount;
+mod serve;
 mod sideband;
+mod tail;
 mod thelio_io;
 mod transition;
+mod trust;
 
 pub use crate::bios::bios;
+pub use crate::bundle::{bundle_export, bundle_import};
+pub use crate::cache::{cache_list, cache_prune, cache_verify, CacheEntry};
+pub use crate::catalog::{firmware_list, FirmwareImage};
 pub use crate::ec::{ec, ec_or_none};
+pub use crate::error::Error;
 pub use crate::me::me;
+pub use crate::mirror::{mirror_sync, MirrorObject, MirrorProject};
+pub use crate::serve::serve;
 pub use crate::thelio_io::{
-    thelio_io_download, thelio_io_list, thelio_io_update, ThelioIo, ThelioIoMetadata,
+    thelio_io_download, thelio_io_list, thelio_io_recover, thelio_io_update,
+    thelio_io_update_device, ThelioIo, ThelioIoMetadata,
 };
 pub use crate::transition::TransitionKind;
 
@@ -169,7 +190,7 @@ const MODEL_WHITELIST: &[&str] = &[
 ];
 
 pub fn model_is_whitelisted(model: &str) -> bool {
-    MODEL_WHITELIST.iter().any(|whitelist| model == *whitelist)
+    MODEL_WHITELIST.contains(&model)
 }
 
 // Helper function for errors
@@ -177,7 +198,7 @@ pub fn err_str<E: ::std::fmt::Display>(err: E) -> String {
     format!("{}", err)
 }
 
-pub fn model_variant(model: &str) -> Result<u8, String> {
+pub fn model_variant(model: &str) -> Result<u8, Error> {
     let pins: &[(u8, u8)] = match model {
         "gaze15" => &[
             // BOARD_ID1 = GPP_G0
@@ -190,7 +211,10 @@ pub fn model_variant(model: &str) -> Result<u8, String> {
 
     let mut variant = 0;
     if !pins.is_empty() {
-        let sideband = unsafe { sideband::Sideband::new(0xFD00_0000)? };
+        let sideband = unsafe {
+            sideband::Sideband::new(0xFD00_0000)
+                .map_err(|err| Error::hardware("failed to read model variant", err))?
+        };
         for (i, pin) in pins.iter().enumerate() {
             let data = unsafe { sideband.gpio(pin.0, pin.1) };
             if data & (1 << 1) > 0 {
@@ -207,25 +231,25 @@ pub fn generate_firmware_id(model: &str, project: &str) -> String {
     format!("{}_{}", model, project_hash)
 }
 
-pub fn firmware_id(transition_kind: TransitionKind) -> Result<String, String> {
+pub fn firmware_id(transition_kind: TransitionKind) -> Result<String, Error> {
     let (bios_model, _bios_version) = bios::bios()?;
     let variant = model_variant(&bios_model)?;
     let (ec_project, _ec_version) = ec_or_none(true);
-    let (transition_model, transition_ec) =
-        transition_kind.transition(&bios_model, variant, &ec_project)?;
+    let (transition_model, transition_ec) = transition_kind
+        .transition(&bios_model, variant, &ec_project)
+        .map_err(|err| Error::hardware("failed to find firmware ID", err))?;
     Ok(generate_firmware_id(&transition_model, &transition_ec))
 }
 
-fn remove_dir<P: AsRef<Path>>(path: P) -> Result<(), String> {
+fn remove_dir<P: AsRef<Path>>(path: P) -> Result<(), Error> {
     if path.as_ref().exists() {
         eprintln!("removing {}", path.as_ref().display());
         match fs::remove_dir_all(&path) {
             Ok(()) => (),
             Err(err) => {
-                return Err(format!(
-                    "failed to remove {}: {}",
-                    path.as_ref().display(),
-                    err
+                return Err(Error::esp(
+                    format!("failed to remove {}", path.as_ref().display()),
+                    err,
                 ));
             }
         }
@@ -234,85 +258,138 @@ fn remove_dir<P: AsRef<Path>>(path: P) -> Result<(), String> {
     Ok(())
 }
 
-pub fn download(transition_kind: TransitionKind) -> Result<(String, String), String> {
-    download_firmware_id(&firmware_id(transition_kind)?)
+pub fn download(transition_kind: TransitionKind) -> Result<(String, String), Error> {
+    download_firmware_id(&firmware_id(transition_kind)?, false)
 }
 
-pub fn download_firmware_id(firmware_id: &str) -> Result<(String, String), String> {
-    let tail_path = Path::new(config::CACHE).join("tail");
-
-    util::retry(
-        || download_firmware_id_(&tail_path, firmware_id),
-        || {
-            fs::remove_file(&tail_path)
-                .context("failed to remove tail cache")
-                .map_err(err_str)
-        },
-    )
+/// Downloads the firmware for `firmware_id`, returning the manifest digest and the changelog.
+/// Failures are retried by `util::RetryPolicy`, and the last one is returned with its class.
+///
+/// The tail is checked by `accept_tail`, which `allow_downgrade` is passed to.
+pub fn download_firmware_id(
+    firmware_id: &str,
+    allow_downgrade: bool,
+) -> Result<(String, String), Error> {
+    let tail_path = tail_cache_path(config::project(), config::branch()).map_err(Error::other)?;
+
+    let result = util::RetryPolicy::default().retry(
+        || download_firmware_id_(&tail_path, firmware_id, allow_downgrade),
+        || remove_tail_cache(&tail_path),
+    );
+    Ok(result?)
 }
 
-fn download_firmware_id_(tail_cache: &Path, firmware_id: &str) -> Result<(String, String), String> {
-    let dl = Downloader::new(
-        config::KEY,
-        config::URL,
-        config::PROJECT,
-        config::BRANCH,
-        Some(config::CERT),
-    )?;
-
-    if !Path::new(config::CACHE).is_dir() {
-        eprintln!("creating cache directory {}", config::CACHE);
-        fs::create_dir(config::CACHE).map_err(err_str)?;
-    }
+fn download_firmware_id_(
+    tail_cache: &Path,
+    firmware_id: &str,
+    allow_downgrade: bool,
+) -> Result<(String, String), Failure> {
+    let trust = Trust::load()?;
 
     eprintln!("downloading tail");
 
-    let fetch_tail = || dl.tail().map_err(|why| anyhow!(why));
-    let tail = cached_block(tail_cache, fetch_tail).map_err(err_str)?;
+    let fetch_tail = || trust.download_tail(config::project(), config::branch());
+    let tail = cached_tail(
+        tail_cache,
+        config::project(),
+        &trust,
+        allow_downgrade,
+        fetch_tail,
+    )
+    .map_err(Failure::from_anyhow)?;
 
     eprintln!("opening download cache");
-    let cache = download::Cache::new(config::CACHE, Some(dl))?;
+    let cache = download::Cache::with_sources(config::cache(), trust.sources()?)?;
 
+    let changelog = firmware_changelog(&cache, &tail.digest, firmware_id)?;
+
+    Ok((tail.digest, changelog))
+}
+
+/// Loads the manifest, updater, and firmware for `firmware_id` through the cache, returning the
+/// changelog of the firmware.
+fn firmware_changelog(
+    cache: &download::Cache,
+    digest: &str,
+    firmware_id: &str,
+) -> Result<String, Failure> {
     eprintln!("downloading manifest.json");
-    let manifest_json = cache.object(&tail.digest)?;
+    let manifest_json = cache.object_named(digest, "manifest.json")?;
     let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(|e| e.to_string())?;
 
-    let _updater_data = {
+    let _updater_file = {
         let file = "system76-firmware-update.tar.xz";
         eprintln!("downloading {}", file);
-        let digest = manifest
-            .files
-            .get(file)
-            .ok_or(format!("{} not found", file))?;
-        cache.object(digest)?
+        let digest = manifest_file(&manifest, file)?;
+        cache.open_named(digest, file)?
     };
 
-    let firmware_data = {
+    let firmware_file = {
         let file = format!("{}.tar.xz", firmware_id);
         eprintln!("downloading {}", file);
-        let digest = manifest
-            .files
-            .get(&file)
-            .ok_or(format!("{} not found", file))?;
-        cache.object(digest)?
+        let digest = manifest_file(&manifest, &file)?;
+        cache.open_named(digest, &file)?
     };
 
     eprintln!("loading changelog.json");
-    let changelog = util::extract_file(&firmware_data, "./changelog.json").map_err(err_str)?;
+    util::extract_file(firmware_file, "./changelog.json").map_err(|err| err_str(err).into())
+}
 
-    Ok((tail.digest, changelog))
+/// Finds the digest of `file` in a manifest, which is not found if the firmware has not been
+/// published.
+pub(crate) fn manifest_file<'a>(manifest: &'a Manifest, file: &str) -> Result<&'a str, Failure> {
+    manifest
+        .files
+        .get(file)
+        .map(String::as_str)
+        .ok_or_else(|| Failure::new(FailureKind::NotFound, format!("{} not found", file)))
 }
 
-/// Retrieves a `Block` from the cached path if it exists and the modified time is recent.
+/// Removes the cached tail of a project, so that the next download fetches a new one.
+pub(crate) fn remove_tail_cache(path: &Path) -> Result<(), String> {
+    match fs::remove_file(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(format!("failed to remove tail cache: {}", err)),
+    }
+}
+
+/// Path of the cached tail of a project branch, laid out like its URL on the buildchain server.
 ///
-/// - If the modified time is older than one day, the cache will be updated.
-/// - The most recent `Block` from cache will be returned after the cache is updated.
-/// - If the cache does not require an update, it will be returned after being deserialized.
-fn cached_block<F: FnMut() -> anyhow::Result<Block>>(
+/// Tails used to be cached in one `tail` file shared by all projects, which is removed here.
+pub(crate) fn tail_cache_path(project: &str, branch: &str) -> Result<PathBuf, String> {
+    tail_path(Path::new(config::cache()), project, branch)
+}
+
+/// Like `tail_cache_path`, in the cache directory `cache`.
+fn tail_path(cache: &Path, project: &str, branch: &str) -> Result<PathBuf, String> {
+    let tail_dir = cache.join("tail");
+    if tail_dir.is_file() {
+        eprintln!("removing shared tail cache {}", tail_dir.display());
+        fs::remove_file(&tail_dir).map_err(err_str)?;
+    }
+
+    let project_dir = tail_dir.join(project);
+    download::create_dir(&project_dir)?;
+
+    Ok(project_dir.join(branch))
+}
+
+/// Retrieves a verified tail from the cached path if it exists and the modified time is recent.
+///
+/// - The cached tail is stored with its signature, which is checked against the keys of `trust`
+///   on every load.
+/// - If the cache is missing, outdated, or fails verification, `func` fetches a new signed
+///   tail, which is verified before it replaces the cache.
+/// - Either tail is checked by `accept_tail` for `project`, with `allow_downgrade`.
+fn cached_tail<F: FnMut() -> Result<SignedTail, Failure>>(
     path: &Path,
+    project: &str,
+    trust: &Trust,
+    allow_downgrade: bool,
     mut func: F,
-) -> anyhow::Result<Block> {
-    let result: anyhow::Result<Block> = (|| {
+) -> anyhow::Result<Tail> {
+    let result: anyhow::Result<Tail> = (|| {
         let modified =
             timestamp::modified_since_unix(path).context("could not get modified time")?;
 
@@ -322,47 +399,87 @@ fn cached_block<F: FnMut() -> anyhow::Result<Block>>(
             return Err(anyhow::anyhow!("timestamp exceeded"));
         }
 
-        let file = fs::File::open(path).context("failed to read cached block")?;
+        let data = fs::read(path).context("failed to read cached tail")?;
+
+        let tail = trust
+            .verify(&SignedTail::new(data))
+            .context("failed to verify cached tail")?;
 
-        bincode::deserialize_from(file).context("failed to deserialize cached block")
+        accept_tail(project, &tail, allow_downgrade)?;
+
+        Ok(tail)
     })();
 
     if result.is_err() {
-        let block = func().context("failed to fetch block")?;
+        let signed = func().context("failed to fetch tail")?;
+        let tail = trust.verify(&signed).context("failed to verify tail")?;
 
-        let file = fs::File::create(path).context("failed to create file for cached block")?;
+        accept_tail(project, &tail, allow_downgrade)?;
 
-        bincode::serialize_into(file, &block).context("failed to cache block")?;
+        download::write(path, signed.as_bytes())
+            .map_err(|why| anyhow!(why))
+            .context("failed to cache tail")?;
 
-        Ok(block)
+        Ok(tail)
     } else {
         result
     }
 }
 
-fn extract<P: AsRef<Path>>(digest: &str, file: &str, path: P) -> Result<(), String> {
-    let cache = download::Cache::new(config::CACHE, None)?;
+/// Refuses a verified tail that is older than the newest one accepted for `project` on the
+/// configured server and branch, that reuses its counter for another manifest, or that is older
+/// than `config::max_tail_age()` if that is set.
+///
+/// With `allow_downgrade`, a tail that fails these checks is accepted with a warning. Like any
+/// other tail, it is only recorded in the history if its counter is newer.
+pub(crate) fn accept_tail(
+    project: &str,
+    tail: &Tail,
+    allow_downgrade: bool,
+) -> Result<(), Failure> {
+    let path = Path::new(config::cache()).join("tail-history.json");
+    let mut history = TailHistory::load(&path)?;
+    let key = history_key(config::url(), project, config::branch());
+
+    if let Err(why) = history.check(&key, tail, timestamp::current(), config::max_tail_age()) {
+        if !allow_downgrade {
+            return Err(Failure::new(
+                FailureKind::Verification,
+                format!("possible rollback: {}", why),
+            ));
+        }
+
+        eprintln!("allowing downgrade: {}", why);
+    }
+
+    if history.accept(&key, tail) {
+        history.save(&path)?;
+    }
+
+    Ok(())
+}
+
+fn extract<P: AsRef<Path>>(digest: &str, file: &str, path: P) -> Result<(), Error> {
+    let cache = download::Cache::new(config::cache(), None).map_err(Error::other)?;
 
     let manifest_json = cache.object(digest)?;
-    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(|e| e.to_string())?;
+    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(Error::other)?;
 
-    let data = {
+    let archive = {
         let digest = manifest
             .files
             .get(file)
-            .ok_or(format!("{} not found", file))?;
-        cache.object(digest)?
+            .ok_or_else(|| Error::other(format!("{} not found", file)))?;
+        cache.open(digest)?
     };
 
     eprintln!("extracting {} to {}", file, path.as_ref().display());
-    match util::extract(&data, &path) {
+    match util::extract(archive, &path) {
         Ok(()) => (),
         Err(err) => {
-            return Err(format!(
-                "failed to extract {} to {}: {}",
-                file,
-                path.as_ref().display(),
-                err
+            return Err(Error::esp(
+                format!("failed to extract {} to {}", file, path.as_ref().display()),
+                err,
             ));
         }
     }
@@ -370,79 +487,171 @@ fn extract<P: AsRef<Path>>(digest: &str, file: &str, path: P) -> Result<(), Stri
     Ok(())
 }
 
-pub fn schedule(
+pub fn schedule(digest: &str, efi_dir: &str, transition_kind: TransitionKind) -> Result<(), Error> {
+    schedule_firmware_id(digest, efi_dir, &firmware_id(transition_kind)?)
+}
+
+pub fn schedule_firmware_id(digest: &str, efi_dir: &str, firmware_id: &str) -> Result<(), Error> {
+    schedule_firmware_id_(digest, efi_dir, firmware_id, false).map(|_| ())
+}
+
+/// Performs every check of `schedule_firmware_id` and extracts the update to a temporary
+/// directory, but only reports the changes that would be made to the ESP and EFI variables.
+pub fn schedule_firmware_id_dry_run(
     digest: &str,
     efi_dir: &str,
-    transition_kind: TransitionKind,
-) -> Result<(), String> {
-    schedule_firmware_id(digest, efi_dir, &firmware_id(transition_kind)?)
+    firmware_id: &str,
+) -> Result<EspChanges, Error> {
+    schedule_firmware_id_(digest, efi_dir, firmware_id, true)
+}
+
+/// Changes to the ESP and EFI variables made when scheduling or cancelling an update.
+#[derive(Clone, Debug, Serialize)]
+pub struct EspChanges {
+    /// The updater directory that is removed, and replaced when scheduling
+    pub updater_dir: PathBuf,
+    /// Files in the updater directory after scheduling, or removed when cancelling
+    pub files: Vec<PathBuf>,
+    /// The `efibootmgr` invocations
+    pub commands: Vec<String>,
 }
 
-pub fn schedule_firmware_id(digest: &str, efi_dir: &str, firmware_id: &str) -> Result<(), String> {
+fn schedule_firmware_id_(
+    digest: &str,
+    efi_dir: &str,
+    firmware_id: &str,
+    dry_run: bool,
+) -> Result<EspChanges, Error> {
     if !Path::new("/sys/firmware/efi").exists() {
-        return Err("must be run using UEFI boot".to_string());
+        return Err(Error::NotUefi);
     }
 
     let updater_file = "system76-firmware-update.tar.xz";
     let firmware_file = format!("{}.tar.xz", firmware_id);
     let updater_dir = Path::new(efi_dir).join("system76-firmware-update");
 
-    boot::unset_next_boot()?;
+    let mut commands = boot::unset_next_boot(dry_run)
+        .map_err(|err| Error::efi_vars("failed to unset next boot", err))?;
+
+    if !dry_run {
+        remove_dir(&updater_dir)?;
+    }
 
-    remove_dir(&updater_dir)?;
+    // A dry run extracts outside of the ESP, to a directory that is removed when dropped
+    let updater_tmp_res = if dry_run {
+        tempfile::TempDir::with_prefix("system76-firmware-update.")
+    } else {
+        tempfile::TempDir::with_prefix_in("system76-firmware-update.", efi_dir)
+    };
 
-    let updater_tmp = match tempfile::TempDir::with_prefix_in("system76-firmware-update.", efi_dir) {
+    let updater_tmp = match updater_tmp_res {
         Ok(ok) => ok,
         Err(err) => {
-            return Err(format!("failed to create temporary directory: {}", err));
+            return Err(Error::esp("failed to create temporary directory", err));
         }
     };
 
     extract(digest, updater_file, updater_tmp.path())?;
 
     // tar will not create a directory if it does not exist in the archive.
-    fs::create_dir(&updater_tmp.path().join("firmware")).map_err(err_str)?;
-    extract(digest, &firmware_file, &updater_tmp.path().join("firmware"))?;
-
-    let updater_tmp_dir = updater_tmp.into_path();
-    eprintln!(
-        "moving {} to {}",
-        updater_tmp_dir.display(),
-        updater_dir.display()
-    );
-    match fs::rename(&updater_tmp_dir, &updater_dir) {
-        Ok(()) => (),
-        Err(err) => {
-            let _ = remove_dir(&updater_tmp_dir);
-            return Err(format!(
-                "failed to move {} to {}: {}",
-                updater_tmp_dir.display(),
-                updater_dir.display(),
-                err
-            ));
+    fs::create_dir(updater_tmp.path().join("firmware"))
+        .map_err(|err| Error::esp("failed to create firmware directory", err))?;
+    extract(digest, &firmware_file, updater_tmp.path().join("firmware"))?;
+
+    let files = util::list_files(updater_tmp.path())
+        .map_err(|err| Error::esp("failed to list updater files", err))?
+        .into_iter()
+        .map(|file| updater_dir.join(file))
+        .collect();
+
+    if !dry_run {
+        let updater_tmp_dir = updater_tmp.keep();
+        eprintln!(
+            "moving {} to {}",
+            updater_tmp_dir.display(),
+            updater_dir.display()
+        );
+        match fs::rename(&updater_tmp_dir, &updater_dir) {
+            Ok(()) => (),
+            Err(err) => {
+                let _ = remove_dir(&updater_tmp_dir);
+                return Err(Error::esp(
+                    format!(
+                        "failed to move {} to {}",
+                        updater_tmp_dir.display(),
+                        updater_dir.display()
+                    ),
+                    err,
+                ));
+            }
         }
     }
 
     // thelio-mira-r1/r2 will not boot to firmware updater unless it is added to BootOrder
     let modify_order =
         firmware_id.starts_with("thelio-mira-r1_") || firmware_id.starts_with("thelio-mira-r2_");
-    boot::set_next_boot(efi_dir, modify_order)?;
+    commands.extend(
+        boot::set_next_boot(efi_dir, modify_order, dry_run)
+            .map_err(|err| Error::efi_vars("failed to set next boot", err))?,
+    );
 
-    eprintln!("Firmware update scheduled. Reboot your machine to install.");
+    if !dry_run {
+        eprintln!("Firmware update scheduled. Reboot your machine to install.");
+    }
 
-    Ok(())
+    Ok(EspChanges {
+        updater_dir,
+        files,
+        commands,
+    })
+}
+
+/// Returns the updater directory in the ESP if a firmware update is scheduled.
+pub fn scheduled(efi_dir: &str) -> Option<PathBuf> {
+    let updater_dir = Path::new(efi_dir).join("system76-firmware-update");
+    if updater_dir.is_dir() {
+        Some(updater_dir)
+    } else {
+        None
+    }
+}
+
+pub fn unschedule(efi_dir: &str) -> Result<(), Error> {
+    unschedule_(efi_dir, false).map(|_| ())
 }
 
-pub fn unschedule(efi_dir: &str) -> Result<(), String> {
+/// Reports the changes that `unschedule` would make to the ESP and EFI variables.
+pub fn unschedule_dry_run(efi_dir: &str) -> Result<EspChanges, Error> {
+    unschedule_(efi_dir, true)
+}
+
+fn unschedule_(efi_dir: &str, dry_run: bool) -> Result<EspChanges, Error> {
     let updater_dir = Path::new(efi_dir).join("system76-firmware-update");
 
-    boot::unset_next_boot()?;
+    let commands = boot::unset_next_boot(dry_run)
+        .map_err(|err| Error::efi_vars("failed to unset next boot", err))?;
+
+    let files = if updater_dir.is_dir() {
+        util::list_files(&updater_dir)
+            .map_err(|err| Error::esp("failed to list updater files", err))?
+            .into_iter()
+            .map(|file| updater_dir.join(file))
+            .collect()
+    } else {
+        Vec::new()
+    };
 
-    remove_dir(updater_dir)?;
+    if !dry_run {
+        remove_dir(&updater_dir)?;
 
-    eprintln!("Firmware update cancelled.");
+        eprintln!("Firmware update cancelled.");
+    }
 
-    Ok(())
+    Ok(EspChanges {
+        updater_dir,
+        files,
+        commands,
+    })
 }
 
 mod timestamp {
@@ -474,3 +683,86 @@ mod timestamp {
         current == 0 || last > current || current - last > limit
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tail::tests::sign_digest;
+
+    const DIGEST: [u8; 48] = [7; 48];
+
+    /// Signs a current tail with the key of `seed`.
+    fn sign(seed: u8, counter: u64) -> (String, SignedTail) {
+        sign_digest(seed, counter, timestamp::current(), &DIGEST)
+    }
+
+    #[test]
+    fn cached_tail_fresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master");
+        let (key, signed) = sign(1, 5);
+        fs::write(&path, signed.as_bytes()).unwrap();
+
+        let trust = Trust::with_keys(vec![key]);
+        let tail = cached_tail(&path, "cached-fresh", &trust, false, || {
+            panic!("a fresh cached tail was fetched again")
+        })
+        .unwrap();
+        assert_eq!(tail.counter, 5);
+    }
+
+    #[test]
+    fn cached_tail_reverified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master");
+        let (key, fetched) = sign(1, 6);
+        let trust = Trust::with_keys(vec![key]);
+
+        // Signed by a key that is no longer trusted, and with a flipped bit
+        let (_, untrusted) = sign(2, 5);
+        let mut corrupt = sign(1, 5).1.as_bytes().to_vec();
+        corrupt[0] ^= 1;
+
+        for cached in [untrusted.as_bytes().to_vec(), corrupt] {
+            fs::write(&path, cached).unwrap();
+            let tail = cached_tail(&path, "cached-reverified", &trust, false, || {
+                Ok(SignedTail::new(fetched.as_bytes().to_vec()))
+            })
+            .unwrap();
+            assert_eq!(tail.counter, 6);
+            assert_eq!(fs::read(&path).unwrap(), fetched.as_bytes());
+        }
+    }
+
+    #[test]
+    fn cached_tail_fetched_unverified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("master");
+        let (key, _) = sign(1, 5);
+        let (_, fetched) = sign(2, 5);
+
+        let trust = Trust::with_keys(vec![key]);
+        assert!(cached_tail(&path, "cached-unverified", &trust, false, || {
+            Ok(SignedTail::new(fetched.as_bytes().to_vec()))
+        })
+        .is_err());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn tail_path_migrates_shared_tail() {
+        let cache = tempfile::tempdir().unwrap();
+        fs::write(cache.path().join("tail"), b"old tail").unwrap();
+
+        let path = tail_path(cache.path(), "firmware", "master").unwrap();
+        assert_eq!(
+            path,
+            cache.path().join("tail").join("firmware").join("master")
+        );
+        assert!(cache.path().join("tail").join("firmware").is_dir());
+        assert!(!path.exists());
+
+        // Later calls keep the layout
+        assert_eq!(tail_path(cache.path(), "firmware", "master").unwrap(), path);
+    }
+}
@@ -4,10 +4,12 @@ use std::path::Path;
 use std::{fs, io};
 use uuid::Uuid;
 
-use crate::err_str;
+use crate::{err_str, Error};
 
+// Only some of the fields are read, the others give the layout of the response
+#[allow(dead_code)]
 #[rustfmt::skip]
-#[repr(packed)]
+#[repr(C, packed)]
 struct PackedResponse(
     u8, u8, u8, u8,
     u16,
@@ -23,7 +25,11 @@ struct PackedResponse(
 
 unsafe impl plain::Plain for PackedResponse {}
 
-pub fn me() -> Result<Option<String>, String> {
+pub fn me() -> Result<Option<String>, Error> {
+    me_().map_err(|err| Error::hardware("failed to read ME", err))
+}
+
+fn me_() -> Result<Option<String>, String> {
     let mei_path = Path::new("/dev/mei0");
     if mei_path.exists() {
         let mut mei_f = fs::OpenOptions::new()
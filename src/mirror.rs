@@ -0,0 +1,357 @@
+use buildchain::{Manifest, Sha384};
+use serde::Serialize;
+use std::fs::{self, File};
+use std::io::Seek;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+use crate::download::{Failure, FailureKind, Source};
+use crate::tail::{history_key, SignedTail, TailHistory};
+use crate::trust::Trust;
+use crate::{config, err_str, timestamp, util, Error};
+
+/// An object added to a mirror.
+#[derive(Clone, Debug, Serialize)]
+pub struct MirrorObject {
+    pub digest: String,
+    /// File name given to the object by the manifest
+    pub file: String,
+}
+
+/// The changes made to the mirror of a project by a sync.
+#[derive(Clone, Debug, Serialize)]
+pub struct MirrorProject {
+    pub project: String,
+    /// Manifest digest of the tail mirrored before, if there was a valid one
+    pub previous: Option<String>,
+    pub digest: String,
+    pub added: Vec<MirrorObject>,
+    /// Number of objects that were already present
+    pub unchanged: usize,
+}
+
+/// Replicates the tails, manifests, and objects of the firmware and Thelio Io projects to `dir`,
+/// in the layout of the buildchain server, so that a static file server can serve it as the URL.
+///
+/// Objects already present with a matching digest are skipped. The tail of each project is
+/// written last, so the mirror never refers to objects it does not have. With `allow_downgrade`,
+/// a tail older than the one mirrored before replaces it.
+pub fn mirror_sync<P: AsRef<Path>>(
+    dir: P,
+    allow_downgrade: bool,
+) -> Result<Vec<MirrorProject>, Error> {
+    let dir = dir.as_ref();
+    let trust = Trust::load().map_err(Error::other)?;
+    let sources = trust.sources().map_err(Error::other)?;
+
+    let mut projects = Vec::new();
+    for project in [config::project(), config::thelio_io_project()] {
+        eprintln!("downloading {} tail", project);
+        let signed_tail = trust.download_tail(project, config::branch())?;
+        projects.push(mirror_project(
+            dir,
+            &trust,
+            &sources,
+            project,
+            signed_tail,
+            allow_downgrade,
+        )?);
+    }
+
+    Ok(projects)
+}
+
+/// Mirrors the manifest and objects of a signed tail of `project` from `sources`, followed by
+/// the tail itself.
+fn mirror_project(
+    dir: &Path,
+    trust: &Trust,
+    sources: &[Source],
+    project: &str,
+    signed_tail: SignedTail,
+    allow_downgrade: bool,
+) -> Result<MirrorProject, Error> {
+    let object_dir = dir.join("object");
+    fs::create_dir_all(&object_dir).map_err(Error::other)?;
+
+    let tail_path = dir.join("tail").join(project).join(config::branch());
+    let previous = fs::read(&tail_path)
+        .ok()
+        .and_then(|data| trust.verify(&SignedTail::new(data)).ok());
+
+    let tail = trust.verify(&signed_tail)?;
+
+    // The rollback checks of the tail history, against the tail mirrored before
+    let key = history_key(config::url(), project, config::branch());
+    let mut history = TailHistory::default();
+    if let Some(previous) = &previous {
+        history.accept(&key, previous);
+    }
+    if let Err(why) = history.check(&key, &tail, timestamp::current(), config::max_tail_age()) {
+        if !allow_downgrade {
+            return Err(Failure::new(
+                FailureKind::Verification,
+                format!("possible rollback: {}", why),
+            )
+            .into());
+        }
+
+        eprintln!("allowing downgrade: {}", why);
+    }
+
+    let mut added = Vec::new();
+    let mut unchanged = 0;
+
+    if is_mirrored(&object_dir, &tail.digest) {
+        unchanged += 1;
+    } else {
+        eprintln!("downloading manifest.json");
+        mirror_object(&object_dir, sources, &tail.digest, "manifest.json").map_err(Error::other)?;
+        added.push(MirrorObject {
+            digest: tail.digest.clone(),
+            file: "manifest.json".to_string(),
+        });
+    }
+
+    let manifest_json = fs::read(object_dir.join(&tail.digest)).map_err(Error::other)?;
+    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(Error::other)?;
+
+    for (file, digest) in manifest.files.iter() {
+        if is_mirrored(&object_dir, digest) {
+            unchanged += 1;
+            continue;
+        }
+
+        eprintln!("downloading {}", file);
+        mirror_object(&object_dir, sources, digest, file).map_err(Error::other)?;
+        added.push(MirrorObject {
+            digest: digest.clone(),
+            file: file.clone(),
+        });
+    }
+
+    write_mirror(&tail_path, signed_tail.as_bytes()).map_err(Error::other)?;
+
+    Ok(MirrorProject {
+        project: project.to_string(),
+        previous: previous.map(|previous| previous.digest),
+        digest: tail.digest,
+        added,
+        unchanged,
+    })
+}
+
+/// Checks that an object is present in the mirror and matches its digest.
+fn is_mirrored(object_dir: &Path, digest: &str) -> bool {
+    File::open(object_dir.join(digest))
+        .and_then(Sha384::new)
+        .is_ok_and(|sha| sha.to_base32() == digest)
+}
+
+/// Streams an object from the first of `sources` that has it to a temporary file of the mirror,
+/// which replaces the object once it matches its digest.
+fn mirror_object(
+    object_dir: &Path,
+    sources: &[Source],
+    digest: &str,
+    name: &str,
+) -> Result<(), String> {
+    let mut errors = Vec::new();
+    for source in sources {
+        let mut file = tempfile::Builder::new()
+            .prefix(&format!("{}.", digest))
+            .suffix(".partial")
+            .tempfile_in(object_dir)
+            .map_err(err_str)?;
+
+        if let Err(failure) = source.fetch(digest, name, file.as_file_mut()) {
+            errors.push(failure.message);
+            continue;
+        }
+
+        file.rewind().map_err(err_str)?;
+        let sha = Sha384::new(&mut file).map_err(err_str)?;
+        if sha.to_base32() != digest {
+            errors.push(format!("{} does not match digest {}", name, digest));
+            continue;
+        }
+
+        // Mirrors are served to everyone
+        let handle = file.as_file();
+        handle
+            .set_permissions(fs::Permissions::from_mode(0o644))
+            .and_then(|()| handle.sync_all())
+            .map_err(err_str)?;
+        file.persist(object_dir.join(digest))
+            .map_err(|err| format!("failed to write {}: {}", digest, err.error))?;
+        return util::sync_dir(object_dir).map_err(err_str);
+    }
+    Err(errors.join(", "))
+}
+
+/// Writes a file of the mirror, which can be served while it is synced.
+fn write_mirror(path: &Path, data: &[u8]) -> Result<(), String> {
+    let parent = path.parent().ok_or("path has no parent")?;
+    fs::create_dir_all(parent).map_err(err_str)?;
+
+    // Mirrors are served to everyone
+    util::write_atomic(path, data, 0o644)
+        .map_err(|err| format!("failed to write {}: {}", path.display(), err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tail::tests::sign_digest;
+    use sha2::Digest;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    /// Publishes `files` and their manifest to an upstream directory, returning the raw digest
+    /// of the manifest.
+    fn publish(upstream: &Path, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let object_dir = upstream.join("object");
+        fs::create_dir_all(&object_dir).unwrap();
+
+        let mut manifest = BTreeMap::new();
+        for (file, data) in files {
+            let digest = Sha384::new(*data).unwrap().to_base32();
+            fs::write(object_dir.join(&digest), data).unwrap();
+            manifest.insert(file.to_string(), digest);
+        }
+
+        let manifest_json = serde_json::to_vec(&Manifest {
+            time: 0,
+            files: manifest,
+        })
+        .unwrap();
+        let digest = Sha384::new(manifest_json.as_slice()).unwrap().to_base32();
+        fs::write(object_dir.join(digest), &manifest_json).unwrap();
+
+        sha2::Sha384::digest(&manifest_json).to_vec()
+    }
+
+    fn sync(
+        mirror: &Path,
+        upstream: &Path,
+        counter: u64,
+        digest: &[u8],
+        allow_downgrade: bool,
+    ) -> Result<MirrorProject, Error> {
+        let (key, signed_tail) = sign_digest(1, counter, timestamp::current(), digest);
+        let sources = [Source::Local(upstream.to_path_buf())];
+        mirror_project(
+            mirror,
+            &Trust::with_keys(vec![key]),
+            &sources,
+            "firmware",
+            signed_tail,
+            allow_downgrade,
+        )
+    }
+
+    fn added(project: &MirrorProject) -> Vec<&str> {
+        project
+            .added
+            .iter()
+            .map(|object| object.file.as_str())
+            .collect()
+    }
+
+    fn tail_path(mirror: &Path) -> PathBuf {
+        mirror.join("tail").join("firmware").join(config::branch())
+    }
+
+    #[test]
+    fn sync_new() {
+        let upstream = tempfile::tempdir().unwrap();
+        let mirror = tempfile::tempdir().unwrap();
+        let digest = publish(upstream.path(), &[("a.tar.xz", b"a"), ("b.tar.xz", b"b")]);
+
+        let project = sync(mirror.path(), upstream.path(), 1, &digest, false).unwrap();
+        assert_eq!(project.previous, None);
+        assert_eq!(added(&project), ["manifest.json", "a.tar.xz", "b.tar.xz"]);
+        assert_eq!(project.unchanged, 0);
+
+        for entry in fs::read_dir(upstream.path().join("object")).unwrap() {
+            let entry = entry.unwrap();
+            let mirrored = mirror.path().join("object").join(entry.file_name());
+            assert_eq!(fs::read(mirrored).unwrap(), fs::read(entry.path()).unwrap());
+        }
+        assert!(tail_path(mirror.path()).is_file());
+    }
+
+    #[test]
+    fn sync_unchanged() {
+        let upstream = tempfile::tempdir().unwrap();
+        let mirror = tempfile::tempdir().unwrap();
+        let digest = publish(upstream.path(), &[("a.tar.xz", b"a")]);
+
+        let first = sync(mirror.path(), upstream.path(), 1, &digest, false).unwrap();
+        let second = sync(mirror.path(), upstream.path(), 1, &digest, false).unwrap();
+        assert_eq!(second.previous, Some(first.digest.clone()));
+        assert_eq!(second.digest, first.digest);
+        assert!(second.added.is_empty());
+        assert_eq!(second.unchanged, 2);
+    }
+
+    #[test]
+    fn sync_changed() {
+        let upstream = tempfile::tempdir().unwrap();
+        let mirror = tempfile::tempdir().unwrap();
+        let old = publish(upstream.path(), &[("a.tar.xz", b"a"), ("b.tar.xz", b"b")]);
+        let first = sync(mirror.path(), upstream.path(), 1, &old, false).unwrap();
+
+        let new = publish(upstream.path(), &[("a.tar.xz", b"a"), ("b.tar.xz", b"b2")]);
+        let second = sync(mirror.path(), upstream.path(), 2, &new, false).unwrap();
+        assert_eq!(second.previous, Some(first.digest));
+        assert_eq!(added(&second), ["manifest.json", "b.tar.xz"]);
+        assert_eq!(second.unchanged, 1);
+    }
+
+    #[test]
+    fn sync_corrupt() {
+        let upstream = tempfile::tempdir().unwrap();
+        let mirror = tempfile::tempdir().unwrap();
+        let digest = publish(upstream.path(), &[("a.tar.xz", b"a")]);
+        sync(mirror.path(), upstream.path(), 1, &digest, false).unwrap();
+
+        let object = Sha384::new(&b"a"[..]).unwrap().to_base32();
+        let path = mirror.path().join("object").join(&object);
+        fs::write(&path, b"corrupt").unwrap();
+
+        let project = sync(mirror.path(), upstream.path(), 1, &digest, false).unwrap();
+        assert_eq!(added(&project), ["a.tar.xz"]);
+        assert_eq!(fs::read(&path).unwrap(), b"a");
+    }
+
+    #[test]
+    fn sync_upstream_mismatch() {
+        let upstream = tempfile::tempdir().unwrap();
+        let mirror = tempfile::tempdir().unwrap();
+        let digest = publish(upstream.path(), &[("a.tar.xz", b"a")]);
+
+        let object = Sha384::new(&b"a"[..]).unwrap().to_base32();
+        fs::write(upstream.path().join("object").join(&object), b"corrupt").unwrap();
+
+        assert!(sync(mirror.path(), upstream.path(), 1, &digest, false).is_err());
+        assert!(!mirror.path().join("object").join(&object).exists());
+        assert!(!tail_path(mirror.path()).exists());
+    }
+
+    #[test]
+    fn sync_rollback() {
+        let upstream = tempfile::tempdir().unwrap();
+        let mirror = tempfile::tempdir().unwrap();
+        let old = publish(upstream.path(), &[("a.tar.xz", b"a")]);
+        let new = publish(upstream.path(), &[("a.tar.xz", b"a2")]);
+        sync(mirror.path(), upstream.path(), 2, &new, false).unwrap();
+
+        // An older tail, or one reusing the counter for another manifest
+        assert!(sync(mirror.path(), upstream.path(), 1, &old, false).is_err());
+        assert!(sync(mirror.path(), upstream.path(), 2, &old, false).is_err());
+
+        let project = sync(mirror.path(), upstream.path(), 1, &old, true).unwrap();
+        assert_eq!(added(&project), ["manifest.json", "a.tar.xz"]);
+    }
+}
@@ -1,9 +1,11 @@
 use std::char;
 use std::ffi::OsString;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+use std::io::{BufRead, BufReader, Error, Result};
 use std::os::unix::ffi::OsStringExt;
 
+// Every field is parsed to check the line, even where only some are used
+#[allow(dead_code)]
 pub struct Mount {
     pub source: OsString,
     pub dest: OsString,
@@ -26,9 +28,9 @@ impl Mount {
                         if let Some(b) = bytes.next() {
                             code *= 8;
                             code += u32::from_str_radix(&(b as char).to_string(), 8)
-                                .map_err(|err| Error::new(ErrorKind::Other, err))?;
+                                .map_err(Error::other)?;
                         } else {
-                            return Err(Error::new(ErrorKind::Other, "truncated octal code"));
+                            return Err(Error::other("truncated octal code"));
                         }
                     }
                     ret.push(code as u8);
@@ -45,24 +47,14 @@ impl Mount {
     fn parse_line(line: &str) -> Result<Mount> {
         let mut parts = line.split(' ');
 
-        let source = parts
-            .next()
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Missing source"))?;
-        let dest = parts
-            .next()
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Missing dest"))?;
-        let fs = parts
-            .next()
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Missing fs"))?;
+        let source = parts.next().ok_or_else(|| Error::other("Missing source"))?;
+        let dest = parts.next().ok_or_else(|| Error::other("Missing dest"))?;
+        let fs = parts.next().ok_or_else(|| Error::other("Missing fs"))?;
         let options = parts
             .next()
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Missing options"))?;
-        let dump = parts
-            .next()
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Missing dump"))?;
-        let pass = parts
-            .next()
-            .ok_or_else(|| Error::new(ErrorKind::Other, "Missing pass"))?;
+            .ok_or_else(|| Error::other("Missing options"))?;
+        let dump = parts.next().ok_or_else(|| Error::other("Missing dump"))?;
+        let pass = parts.next().ok_or_else(|| Error::other("Missing pass"))?;
 
         Ok(Mount {
             source: Self::parse_value(source)?,
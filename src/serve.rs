@@ -0,0 +1,247 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::{config, download, Error};
+
+/// Most connections served at once. Others are closed as soon as they are accepted.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Most bytes read of the request line and headers, which are all a request consists of.
+const MAX_REQUEST: u64 = 8 * 1024;
+
+/// Time a client may take to send its request, or to receive each part of the response.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Counts a connection as served until it is dropped.
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    fn new(connections: &Arc<AtomicUsize>) -> Option<Self> {
+        connections
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                Some(count + 1).filter(|&count| count <= MAX_CONNECTIONS)
+            })
+            .ok()
+            .map(|_| Connection(connections.clone()))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Serves the download cache and the cached tails over HTTP on `addr`, in the layout of the
+/// buildchain server, so that other machines can use it as their configured URL.
+///
+/// Nothing served is trusted by the clients, which verify tails with their keys and objects with
+/// their digests. This only returns if the address cannot be bound.
+pub fn serve(addr: &str) -> Result<(), Error> {
+    let listener = TcpListener::bind(addr)
+        .map_err(|err| Error::other(format!("failed to listen on {}: {}", addr, err)))?;
+
+    eprintln!(
+        "serving {} on {}",
+        config::cache(),
+        listener.local_addr().map_err(Error::other)?
+    );
+
+    let cache = Path::new(config::cache());
+    let connections = Arc::new(AtomicUsize::new(0));
+    for stream_res in listener.incoming() {
+        let stream = match stream_res {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("failed to accept connection: {}", err);
+                continue;
+            }
+        };
+
+        let peer = stream.peer_addr().ok();
+        let connection = match Connection::new(&connections) {
+            Some(connection) => connection,
+            None => {
+                eprintln!("too many connections, closing {:?}", peer);
+                continue;
+            }
+        };
+
+        thread::spawn(move || {
+            let _connection = connection;
+            if let Err(err) = handle(cache, stream) {
+                eprintln!("failed to serve {:?}: {}", peer, err);
+            }
+        });
+    }
+
+    Ok(())
+}
+
+fn handle(cache: &Path, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?.take(MAX_REQUEST));
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    if !request.ends_with('\n') {
+        return respond(&mut stream, "400 Bad Request", None);
+    }
+
+    // Headers are not needed, but are read so the client is not reset before the response
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return respond(&mut stream, "400 Bad Request", None),
+    };
+
+    if method != "GET" && method != "HEAD" {
+        return respond(&mut stream, "405 Method Not Allowed", None);
+    }
+
+    let file = resolve(cache, target)
+        .and_then(|path| File::open(path).ok())
+        .filter(|file| file.metadata().is_ok_and(|metadata| metadata.is_file()));
+    match file {
+        Some(file) => {
+            eprintln!("{} {}", method, target);
+            respond(&mut stream, "200 OK", Some((file, method == "GET")))
+        }
+        None => respond(&mut stream, "404 Not Found", None),
+    }
+}
+
+/// Maps a request to a file in `cache`, matching the end of the path so that clients may use any
+/// prefix.
+fn resolve(cache: &Path, target: &str) -> Option<PathBuf> {
+    let path = target.split(['?', '#']).next()?;
+    let segments = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+
+    match segments.as_slice() {
+        [.., "object", digest] if download::is_digest(digest) => Some(cache.join(digest)),
+        [.., "tail", project, branch] if is_name(project) && is_name(branch) => {
+            Some(cache.join("tail").join(project).join(branch))
+        }
+        _ => None,
+    }
+}
+
+/// Whether `name` is a plain project or branch name, which cannot leave the tail directory.
+fn is_name(name: &str) -> bool {
+    !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-_.".contains(&b))
+}
+
+fn respond(stream: &mut TcpStream, status: &str, body: Option<(File, bool)>) -> io::Result<()> {
+    let length = match &body {
+        Some((file, _)) => file.metadata()?.len(),
+        None => 0,
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status, length
+    )?;
+
+    if let Some((mut file, true)) = body {
+        io::copy(&mut file, stream)?;
+    }
+
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    #[test]
+    fn resolve_object() {
+        let cache = Path::new("/cache");
+        let expected = Some(cache.join(DIGEST));
+        assert_eq!(resolve(cache, &format!("/object/{}", DIGEST)), expected);
+        assert_eq!(
+            resolve(cache, &format!("/buildchain/object/{}?x=1", DIGEST)),
+            expected
+        );
+        assert_eq!(resolve(cache, "/object/tail-history.json"), None);
+        assert_eq!(resolve(cache, "/object/.."), None);
+        assert_eq!(resolve(cache, "/object/"), None);
+    }
+
+    #[test]
+    fn resolve_tail() {
+        let cache = Path::new("/cache");
+        assert_eq!(
+            resolve(cache, "/buildchain/tail/firmware/master"),
+            Some(cache.join("tail").join("firmware").join("master"))
+        );
+        assert_eq!(resolve(cache, "/tail/../master"), None);
+        assert_eq!(resolve(cache, "/tail/firmware/.."), None);
+        assert_eq!(resolve(cache, "/tail/firmware/%2e%2e"), None);
+        assert_eq!(resolve(cache, "/tail/firmware"), None);
+        assert_eq!(resolve(cache, "/tail-history.json"), None);
+        assert_eq!(resolve(cache, "/"), None);
+    }
+
+    /// Sends `request` to `handle` over a loopback connection, returning the response.
+    fn exchange(request: &[u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        client.write_all(request).unwrap();
+        handle(Path::new("/nonexistent"), server).unwrap();
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn handle_not_found() {
+        let response = exchange(b"GET /tail/firmware/master HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 "), "{}", response);
+    }
+
+    #[test]
+    fn handle_oversized_request() {
+        // Without the limit, this would wait for the rest of the line until the read times out
+        let response = exchange(&vec![b'a'; MAX_REQUEST as usize]);
+        assert!(response.starts_with("HTTP/1.1 400 "), "{}", response);
+    }
+
+    #[test]
+    fn names() {
+        assert!(is_name("firmware"));
+        assert!(is_name("thelio-io-firmware"));
+        assert!(is_name("v1.2_beta"));
+        assert!(!is_name(".."));
+        assert!(!is_name(".hidden"));
+        assert!(!is_name("a/b"));
+        assert!(!is_name("a\\b"));
+        assert!(!is_name("%2e%2e"));
+        assert!(!is_name("a\0b"));
+    }
+}
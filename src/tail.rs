@@ -0,0 +1,367 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::{fs, io};
+
+use crate::download::{self, Failure, FailureKind};
+use crate::err_str;
+
+const ALPHABET: base32::Alphabet = base32::Alphabet::RFC4648 { padding: false };
+
+const SIGNATURE_SIZE: usize = 64;
+const PUBLIC_KEY_SIZE: usize = 32;
+const DIGEST_SIZE: usize = 48;
+
+// A signed block is the signature followed by the public key, the previous signature, the
+// counter, the timestamp, and the SHA-384 digest of the manifest
+pub(crate) const BLOCK_SIZE: usize =
+    SIGNATURE_SIZE + PUBLIC_KEY_SIZE + SIGNATURE_SIZE + 8 + 8 + DIGEST_SIZE;
+
+/// The contents of a tail block whose signature has been verified.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Tail {
+    pub counter: u64,
+    pub timestamp: u64,
+    pub digest: String,
+}
+
+/// A tail block as served by buildchain, with its signature attached.
+#[derive(Clone, Debug)]
+pub struct SignedTail(Vec<u8>);
+
+impl SignedTail {
+    pub fn new(data: Vec<u8>) -> Self {
+        SignedTail(data)
+    }
+
+    /// Downloads the tail block of a project branch, trusting `certs` for the connection, or
+    /// reads it if `url` is a local mirror.
+    pub fn download(
+        url: &str,
+        project: &str,
+        branch: &str,
+        certs: &[Vec<u8>],
+    ) -> Result<Self, Failure> {
+        if let Some(dir) = download::local_mirror(url) {
+            let path = dir.join("tail").join(project).join(branch);
+            return fs::read(&path).map(SignedTail).map_err(|err| {
+                let kind = if err.kind() == io::ErrorKind::NotFound {
+                    FailureKind::NotFound
+                } else {
+                    FailureKind::Other
+                };
+                Failure::new(kind, format!("failed to read {}: {}", path.display(), err))
+            });
+        }
+
+        let mut builder = reqwest::blocking::Client::builder();
+        for cert in certs {
+            builder = builder
+                .add_root_certificate(reqwest::Certificate::from_pem(cert).map_err(err_str)?);
+        }
+        let client = builder.build().map_err(err_str)?;
+
+        let url = format!("{}/tail/{}/{}", url.trim_end_matches('/'), project, branch);
+        let data = client
+            .get(&url)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.bytes())
+            .map_err(|err| Failure::request(&url, err))?;
+
+        Ok(SignedTail(data.to_vec()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Checks the signature against the base32 encoded public `key`.
+    ///
+    /// buildchain only verifies the tails its `Downloader` fetches, against a single key, so
+    /// tails read from the cache, bundles, and mirrors are verified here with the same layout.
+    pub fn verify(&self, key: &str) -> Result<Tail, String> {
+        let public_key = decode_key(key)?;
+
+        if self.0.len() != BLOCK_SIZE {
+            return Err(format!(
+                "tail block is {} bytes instead of {}",
+                self.0.len(),
+                BLOCK_SIZE
+            ));
+        }
+
+        let mut block = vec![0; self.0.len()];
+        let count = sodalite::sign_attached_open(&mut block, &self.0, &public_key)
+            .map_err(|()| "tail block signature is not valid".to_string())?;
+        let block = &block[..count];
+
+        let (block_key, block) = block.split_at(PUBLIC_KEY_SIZE);
+        if block_key != public_key {
+            return Err("tail block was signed by an unexpected key".to_string());
+        }
+
+        let (_previous, block) = block.split_at(SIGNATURE_SIZE);
+        let (counter, block) = block.split_at(8);
+        let (timestamp, digest) = block.split_at(8);
+
+        Ok(Tail {
+            counter: u64::from_le_bytes(counter.try_into().unwrap()),
+            timestamp: u64::from_le_bytes(timestamp.try_into().unwrap()),
+            digest: base32::encode(ALPHABET, digest),
+        })
+    }
+}
+
+/// Identifies the tails of a project branch on a server by the URL of the tail, as the counters
+/// of different servers and branches are unrelated.
+pub fn history_key(url: &str, project: &str, branch: &str) -> String {
+    format!("{}/tail/{}/{}", url.trim_end_matches('/'), project, branch)
+}
+
+/// The newest tail accepted for each `history_key`, so that older tails can be refused.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct TailHistory(BTreeMap<String, Tail>);
+
+impl TailHistory {
+    /// Loads the history from `path`, which is empty if the file does not exist.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let mut history: Self = match fs::read(path) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|err| format!("failed to parse {}: {}", path.display(), err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(format!("failed to read {}: {}", path.display(), err)),
+        };
+
+        // Tails used to be recorded by project alone, regardless of server and branch
+        history.0.retain(|key, _| key.contains("/tail/"));
+
+        Ok(history)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let data = serde_json::to_vec_pretty(self).map_err(err_str)?;
+        download::write(path, &data)
+    }
+
+    /// Checks that `tail` does not go back from the newest tail accepted for `key`, and that it
+    /// was signed no more than `max_age` seconds before `now`.
+    pub fn check(&self, key: &str, tail: &Tail, now: u64, max_age: u64) -> Result<(), String> {
+        if let Some(newest) = self.0.get(key) {
+            if tail.counter < newest.counter || tail.timestamp < newest.timestamp {
+                return Err(format!(
+                    "{}: tail {} at {} is older than tail {} at {}",
+                    key, tail.counter, tail.timestamp, newest.counter, newest.timestamp
+                ));
+            }
+
+            if tail.counter == newest.counter && tail.digest != newest.digest {
+                return Err(format!(
+                    "{}: tail {} has a different digest than before",
+                    key, tail.counter
+                ));
+            }
+        }
+
+        if max_age > 0 && now.saturating_sub(tail.timestamp) > max_age {
+            return Err(format!(
+                "{}: tail {} was signed more than {} seconds ago",
+                key, tail.counter, max_age
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Records `tail` if it is newer than the newest tail accepted for `key`, returning whether
+    /// the history changed.
+    pub fn accept(&mut self, key: &str, tail: &Tail) -> bool {
+        match self.0.get(key) {
+            Some(newest) if newest.counter >= tail.counter => false,
+            _ => {
+                self.0.insert(key.to_string(), tail.clone());
+                true
+            }
+        }
+    }
+}
+
+pub(crate) fn decode_key(key: &str) -> Result<[u8; PUBLIC_KEY_SIZE], String> {
+    base32::decode(ALPHABET, key)
+        .and_then(|bytes| bytes.as_slice().try_into().ok())
+        .ok_or_else(|| format!("invalid public key: {}", key))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    const DIGEST: [u8; DIGEST_SIZE] = [7; DIGEST_SIZE];
+
+    fn sign(seed: u8, counter: u64, timestamp: u64) -> (String, SignedTail) {
+        sign_digest(seed, counter, timestamp, &DIGEST)
+    }
+
+    /// Signs a block as buildchain does, returning the base32 public key and the signed tail.
+    pub(crate) fn sign_digest(
+        seed: u8,
+        counter: u64,
+        timestamp: u64,
+        digest: &[u8],
+    ) -> (String, SignedTail) {
+        let mut public_key = [0; PUBLIC_KEY_SIZE];
+        let mut secret_key = [0; 64];
+        sodalite::sign_keypair_seed(&mut public_key, &mut secret_key, &[seed; 32]);
+
+        let mut block = Vec::new();
+        block.extend_from_slice(&public_key);
+        block.extend_from_slice(&[0; SIGNATURE_SIZE]);
+        block.extend_from_slice(&counter.to_le_bytes());
+        block.extend_from_slice(&timestamp.to_le_bytes());
+        block.extend_from_slice(digest);
+
+        let mut signed = vec![0; SIGNATURE_SIZE + block.len()];
+        sodalite::sign_attached(&mut signed, &block, &secret_key);
+        (
+            base32::encode(ALPHABET, &public_key),
+            SignedTail::new(signed),
+        )
+    }
+
+    #[test]
+    fn verify_valid() {
+        let (key, signed) = sign(1, 5, 1000);
+        let tail = signed.verify(&key).unwrap();
+        assert_eq!(tail.counter, 5);
+        assert_eq!(tail.timestamp, 1000);
+        assert_eq!(tail.digest, base32::encode(ALPHABET, &DIGEST));
+    }
+
+    #[test]
+    fn verify_wrong_key() {
+        let (_key, signed) = sign(1, 5, 1000);
+        let (other_key, _) = sign(2, 5, 1000);
+        assert!(signed.verify(&other_key).is_err());
+    }
+
+    #[test]
+    fn verify_truncated() {
+        let (key, signed) = sign(1, 5, 1000);
+        let data = signed.as_bytes();
+        assert!(SignedTail::new(data[..data.len() - 1].to_vec())
+            .verify(&key)
+            .is_err());
+        assert!(SignedTail::new(Vec::new()).verify(&key).is_err());
+    }
+
+    #[test]
+    fn verify_bit_flipped() {
+        let (key, signed) = sign(1, 5, 1000);
+        // The first byte of the signature, and the last byte of the digest
+        for index in [0, BLOCK_SIZE - 1] {
+            let mut data = signed.as_bytes().to_vec();
+            data[index] ^= 1;
+            assert!(SignedTail::new(data).verify(&key).is_err());
+        }
+    }
+
+    #[test]
+    fn verify_invalid_key() {
+        let (_key, signed) = sign(1, 5, 1000);
+        assert!(signed.verify("not a key").is_err());
+    }
+
+    const MAX_AGE: u64 = 100;
+
+    fn tail(counter: u64, timestamp: u64, digest: &str) -> Tail {
+        Tail {
+            counter,
+            timestamp,
+            digest: digest.to_string(),
+        }
+    }
+
+    fn history(key: &str, newest: &Tail) -> TailHistory {
+        let mut history = TailHistory::default();
+        assert!(history.accept(key, newest));
+        history
+    }
+
+    #[test]
+    fn check_counter_backwards() {
+        let key = history_key("https://example.com/", "firmware", "master");
+        let history = history(&key, &tail(10, 1000, "a"));
+        assert!(history
+            .check(&key, &tail(9, 1000, "b"), 1000, MAX_AGE)
+            .is_err());
+        assert!(history
+            .check(&key, &tail(11, 999, "b"), 1000, MAX_AGE)
+            .is_err());
+        assert!(history
+            .check(&key, &tail(11, 1001, "b"), 1001, MAX_AGE)
+            .is_ok());
+    }
+
+    #[test]
+    fn check_equal_counter() {
+        let key = history_key("https://example.com", "firmware", "master");
+        let history = history(&key, &tail(10, 1000, "a"));
+        assert!(history
+            .check(&key, &tail(10, 1000, "a"), 1000, MAX_AGE)
+            .is_ok());
+        assert!(history
+            .check(&key, &tail(10, 1000, "b"), 1000, MAX_AGE)
+            .is_err());
+    }
+
+    #[test]
+    fn check_stale_timestamp() {
+        let key = history_key("https://example.com", "firmware", "master");
+        let history = TailHistory::default();
+        assert!(history
+            .check(&key, &tail(1, 1000, "a"), 1100, MAX_AGE)
+            .is_ok());
+        assert!(history
+            .check(&key, &tail(1, 1000, "a"), 1101, MAX_AGE)
+            .is_err());
+
+        // A maximum age of zero disables the check
+        assert!(history
+            .check(&key, &tail(1, 1000, "a"), u64::MAX, 0)
+            .is_ok());
+    }
+
+    #[test]
+    fn check_branch_switch() {
+        let master = history_key("https://example.com", "firmware", "master");
+        let history = history(&master, &tail(10, 1000, "a"));
+
+        let beta = history_key("https://example.com", "firmware", "beta");
+        assert!(history
+            .check(&beta, &tail(3, 900, "b"), 1000, MAX_AGE)
+            .is_ok());
+
+        let staging = history_key("https://staging.example.com", "firmware", "master");
+        assert!(history
+            .check(&staging, &tail(3, 900, "b"), 1000, MAX_AGE)
+            .is_ok());
+    }
+
+    #[test]
+    fn download_local() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_key, signed) = sign(1, 5, 1000);
+        let project_dir = dir.path().join("tail").join("firmware");
+        fs::create_dir_all(&project_dir).unwrap();
+        fs::write(project_dir.join("master"), signed.as_bytes()).unwrap();
+
+        let path = dir.path().to_str().unwrap();
+        for url in [format!("file://{}", path), path.to_string()] {
+            let downloaded = SignedTail::download(&url, "firmware", "master", &[]).unwrap();
+            assert_eq!(downloaded.as_bytes(), signed.as_bytes());
+
+            let failure = SignedTail::download(&url, "firmware", "beta", &[]).unwrap_err();
+            assert_eq!(failure.kind, FailureKind::NotFound);
+        }
+    }
+}
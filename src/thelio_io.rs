@@ -1,19 +1,26 @@
-use anyhow::Context;
-use buildchain::{Downloader, Manifest};
+use buildchain::Manifest;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::{fs, io, process, thread, time};
 
-use crate::{config, download, err_str};
+use crate::download::Failure;
+use crate::tail::SignedTail;
+use crate::trust::Trust;
+use crate::{config, download, Error};
+
+/// Lists the Thelio Io devices, classing a failure to do so.
+fn all_devices() -> Result<Vec<ThelioIo>, Error> {
+    ThelioIo::all().map_err(|err| Error::thelio_io("failed to list Thelio Io devices", err))
+}
 
 fn read_file<P: AsRef<Path>>(path: P) -> io::Result<String> {
     fs::read_to_string(path).map(|x| x.trim().to_string())
 }
 
 fn check_file<P: AsRef<Path>>(path: P, value: &str) -> bool {
-    read_file(path).ok().map_or(false, |x| x == value)
+    read_file(path).is_ok_and(|x| x == value)
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -39,10 +46,10 @@ impl ThelioIoBootloader {
         if status.success() {
             Ok(())
         } else {
-            Err(io::Error::new(
-                io::ErrorKind::Other,
-                format!("dfu-programmer exited with {}", status),
-            ))
+            Err(io::Error::other(format!(
+                "dfu-programmer exited with {}",
+                status
+            )))
         }
     }
 
@@ -165,46 +172,53 @@ impl ThelioIo {
             ThelioIo::Normal(normal) => &normal.0,
         }
     }
-}
-
-pub fn thelio_io_download() -> Result<(String, String), String> {
-    let tail_cache = Path::new(config::CACHE).join("tail");
 
-    crate::util::retry(
-        || thelio_io_download_(&tail_cache),
-        || {
-            fs::remove_file(&tail_cache)
-                .context("failed to remove thelio I/O tail cache")
-                .map_err(err_str)
-        },
-    )
+    /// Whether this device is at the sysfs path `device`, which may also be just the device name.
+    pub fn matches(&self, device: &Path) -> bool {
+        let path = self.path();
+        path == device || path.file_name() == Some(device.as_os_str())
+    }
 }
 
-fn thelio_io_download_(tail_cache: &Path) -> Result<(String, String), String> {
-    let dl = Downloader::new(
-        config::KEY,
-        config::URL,
-        config::THELIO_IO_PROJECT,
-        config::BRANCH,
-        Some(config::CERT),
-    )?;
+/// Downloads the newest Thelio Io firmware, returning the manifest digest and its revision. The
+/// tail is checked by `accept_tail` with `allow_downgrade`, like that of the firmware.
+pub fn thelio_io_download(allow_downgrade: bool) -> Result<(String, String), Error> {
+    let tail_cache = crate::tail_cache_path(config::thelio_io_project(), config::branch())
+        .map_err(Error::other)?;
+
+    let result = crate::util::RetryPolicy::default().retry(
+        || thelio_io_download_(&tail_cache, allow_downgrade),
+        || crate::remove_tail_cache(&tail_cache),
+    );
+    Ok(result?)
+}
 
-    let fetch_tail = || dl.tail().map_err(|why| anyhow!(why));
-    let tail = crate::cached_block(tail_cache, fetch_tail).map_err(err_str)?;
-    let cache = download::Cache::new(config::CACHE, Some(dl))?;
+fn thelio_io_download_(
+    tail_cache: &Path,
+    allow_downgrade: bool,
+) -> Result<(String, String), Failure> {
+    let trust = Trust::load()?;
+
+    let fetch_tail = || trust.download_tail(config::thelio_io_project(), config::branch());
+    let tail = crate::cached_tail(
+        tail_cache,
+        config::thelio_io_project(),
+        &trust,
+        allow_downgrade,
+        fetch_tail,
+    )
+    .map_err(Failure::from_anyhow)?;
+    let cache = download::Cache::with_sources(config::cache(), trust.sources()?)?;
 
     eprintln!("downloading manifest.json");
-    let manifest_json = cache.object(&tail.digest)?;
+    let manifest_json = cache.object_named(&tail.digest, "manifest.json")?;
     let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(|e| e.to_string())?;
 
     let metadata_json = {
         let file = "metadata.json";
         eprintln!("downloading {}", file);
-        let digest = manifest
-            .files
-            .get(file)
-            .ok_or(format!("{} not found", file))?;
-        cache.object(digest)?
+        let digest = crate::manifest_file(&manifest, file)?;
+        cache.object_named(digest, file)?
     };
     let metadata =
         serde_json::from_slice::<ThelioIoMetadata>(&metadata_json).map_err(|e| e.to_string())?;
@@ -212,23 +226,20 @@ fn thelio_io_download_(tail_cache: &Path) -> Result<(String, String), String> {
     let _firmware_data = {
         let file = "main.hex";
         eprintln!("downloading {}", file);
-        let digest = manifest
-            .files
-            .get(file)
-            .ok_or(format!("{} not found", file))?;
-        cache.object(digest)?
+        let digest = crate::manifest_file(&manifest, file)?;
+        cache.object_named(digest, file)?
     };
 
     Ok((tail.digest, metadata.revision))
 }
 
-pub fn thelio_io_list() -> Result<HashMap<String, String>, String> {
+pub fn thelio_io_list() -> Result<HashMap<String, String>, Error> {
     let mut map = HashMap::new();
-    for item in ThelioIo::all().map_err(err_str)? {
+    for item in all_devices()? {
         let path_str = {
             let path = item.path();
             path.to_str()
-                .ok_or(format!("invalid path: {:?}", path))?
+                .ok_or_else(|| Error::thelio_io("invalid path", format!("{:?}", path)))?
                 .to_owned()
         };
         let revision = match item {
@@ -240,35 +251,34 @@ pub fn thelio_io_list() -> Result<HashMap<String, String>, String> {
     Ok(map)
 }
 
-pub fn thelio_io_update(digest: &str) -> Result<(), String> {
-    let cache = download::Cache::new(config::CACHE, None)?;
-
-    let manifest_json = cache.object(digest)?;
-    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(err_str)?;
-
-    let metadata_json = {
-        let file = "metadata.json";
-        let digest = manifest
-            .files
-            .get(file)
-            .ok_or(format!("{} not found", file))?;
-        cache.object(digest)?
-    };
-    let metadata =
-        serde_json::from_slice::<ThelioIoMetadata>(&metadata_json).map_err(|e| e.to_string())?;
+pub fn thelio_io_update(digest: &str) -> Result<(), Error> {
+    thelio_io_update_device(digest, None)
+}
 
-    let firmware_data = {
-        let file = "main.hex";
-        let digest = manifest
-            .files
-            .get(file)
-            .ok_or(format!("{} not found", file))?;
-        cache.object(digest)?
-    };
+/// Updates the Thelio Io at the sysfs path `device`, or every Thelio Io if `None`.
+pub fn thelio_io_update_device(digest: &str, device: Option<&Path>) -> Result<(), Error> {
+    let cache = download::Cache::new(config::cache(), None).map_err(Error::other)?;
+    let (metadata, firmware_data) = thelio_io_firmware(&cache, digest)?;
+
+    if let Some(device) = device {
+        if !all_devices()?
+            .iter()
+            .any(|thelio_io| thelio_io.matches(device))
+        {
+            return Err(Error::thelio_io(
+                "Thelio Io not found",
+                device.display().to_string(),
+            ));
+        }
+    }
 
     eprintln!("Switching devices to bootloader");
     let mut sleep = false;
-    for thelio_io in ThelioIo::all().map_err(err_str)? {
+    for thelio_io in all_devices()? {
+        if device.is_some_and(|device| !thelio_io.matches(device)) {
+            continue;
+        }
+
         eprintln!(" {:?}", thelio_io.path());
         match thelio_io {
             ThelioIo::Bootloader(_) => {
@@ -279,7 +289,9 @@ pub fn thelio_io_update(digest: &str) -> Result<(), String> {
                 eprintln!("  revision: {:?}", revision);
                 if revision != metadata.revision {
                     eprintln!("  switching to bootloader");
-                    normal.bootloader().map_err(err_str)?;
+                    normal
+                        .bootloader()
+                        .map_err(|err| Error::thelio_io("failed to switch to bootloader", err))?;
                     sleep = true;
                 } else {
                     eprintln!("  already up to date");
@@ -293,15 +305,79 @@ pub fn thelio_io_update(digest: &str) -> Result<(), String> {
         thread::sleep(time::Duration::new(5, 0));
     }
 
+    thelio_io_flash(&metadata, &firmware_data, device)
+}
+
+/// Flashes every Thelio Io stuck in the bootloader with the firmware of the cached tail, without
+/// contacting the server. Returns the digest and revision of the firmware.
+pub fn thelio_io_recover() -> Result<(String, String), Error> {
+    let cache = download::Cache::new(config::cache(), None).map_err(Error::other)?;
+    let digest = thelio_io_cached_digest().map_err(Error::other)?;
+    let (metadata, firmware_data) = thelio_io_firmware(&cache, &digest)?;
+
+    thelio_io_flash(&metadata, &firmware_data, None)?;
+
+    Ok((digest, metadata.revision))
+}
+
+/// Finds the manifest of the cached Thelio Io tail, which is verified again, but not checked
+/// for its age, so that a device can be recovered offline.
+fn thelio_io_cached_digest() -> Result<String, String> {
+    let path = crate::tail_cache_path(config::thelio_io_project(), config::branch())?;
+    let data =
+        fs::read(&path).map_err(|err| format!("Thelio Io tail not found in cache: {}", err))?;
+    let tail = Trust::load()?.verify(&SignedTail::new(data))?;
+    Ok(tail.digest)
+}
+
+/// Loads the metadata and firmware of a Thelio Io manifest from the cache.
+fn thelio_io_firmware(
+    cache: &download::Cache,
+    digest: &str,
+) -> Result<(ThelioIoMetadata, Vec<u8>), Error> {
+    let manifest_json = cache.object(digest)?;
+    let manifest = serde_json::from_slice::<Manifest>(&manifest_json).map_err(Error::other)?;
+
+    let metadata_json = {
+        let file = "metadata.json";
+        let digest = crate::manifest_file(&manifest, file)?;
+        cache.object(digest)?
+    };
+    let metadata =
+        serde_json::from_slice::<ThelioIoMetadata>(&metadata_json).map_err(Error::other)?;
+
+    let firmware_data = {
+        let file = "main.hex";
+        let digest = crate::manifest_file(&manifest, file)?;
+        cache.object(digest)?
+    };
+
+    Ok((metadata, firmware_data))
+}
+
+/// Flashes Thelio Io devices in the bootloader, limited to `device` if given.
+fn thelio_io_flash(
+    metadata: &ThelioIoMetadata,
+    firmware_data: &[u8],
+    device: Option<&Path>,
+) -> Result<(), Error> {
     eprintln!("Flashing devices");
-    sleep = false;
-    for thelio_io in ThelioIo::all().map_err(err_str)? {
+    let mut sleep = false;
+    for thelio_io in all_devices()? {
+        if device.is_some_and(|device| !thelio_io.matches(device)) {
+            continue;
+        }
+
         eprintln!(" {:?}", thelio_io.path());
         match thelio_io {
             ThelioIo::Bootloader(bootloader) => {
                 eprintln!("  flashing: {}", metadata.revision);
-                bootloader.flash(&firmware_data).map_err(err_str)?;
-                bootloader.reset().map_err(err_str)?;
+                bootloader
+                    .flash(firmware_data)
+                    .map_err(|err| Error::thelio_io("failed to flash", err))?;
+                bootloader
+                    .reset()
+                    .map_err(|err| Error::thelio_io("failed to reset", err))?;
                 sleep = true;
             }
             ThelioIo::Normal(_) => {
@@ -316,7 +392,7 @@ pub fn thelio_io_update(digest: &str) -> Result<(), String> {
     }
 
     eprintln!("Enumerating devices");
-    for thelio_io in ThelioIo::all().map_err(err_str)? {
+    for thelio_io in all_devices()? {
         eprintln!(" {:?}", thelio_io.path());
         match thelio_io {
             ThelioIo::Bootloader(_) => {
@@ -47,6 +47,18 @@ impl Transition {
     }
 }
 
+/// EC projects that firmware IDs are known to be generated from: the open EC, the proprietary
+/// ECs of models with transitions, and "none" for models without an EC.
+pub(crate) fn known_projects() -> Vec<&'static str> {
+    let mut projects = vec!["none", "76ec"];
+    for transition in TRANSITIONS.iter() {
+        if !projects.contains(&transition.proprietary) {
+            projects.push(transition.proprietary);
+        }
+    }
+    projects
+}
+
 #[derive(Clone, Copy, Debug)]
 pub enum TransitionKind {
     /// Whatever the default is
@@ -0,0 +1,158 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config;
+use crate::download::{self, Failure, FailureKind, Source};
+use crate::tail::{self, SignedTail, Tail};
+
+/// Certificates of the buildchain server and keys signing its tails that are trusted, so that
+/// either can be rotated on the server without stranding installed machines.
+pub struct Trust {
+    keys: Vec<String>,
+    certs: Vec<Vec<u8>>,
+}
+
+impl Trust {
+    /// Loads the compiled in key and certificate, followed by the keys of the configuration and
+    /// the `*.key` and `*.pem` files in `config::trust_dir()`. Keys and drop-ins that cannot be
+    /// parsed are skipped with a warning.
+    pub fn load() -> Result<Self, String> {
+        let mut trust = Trust {
+            keys: vec![config::KEY.to_string()],
+            certs: vec![config::CERT.to_vec()],
+        };
+
+        for key in &config::Config::get().keys {
+            match tail::decode_key(key) {
+                Ok(_) => trust.keys.push(key.clone()),
+                Err(err) => eprintln!("ignoring configured key: {}", err),
+            }
+        }
+
+        let dir = Path::new(config::trust_dir());
+        if !dir.is_dir() {
+            return Ok(trust);
+        }
+
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .and_then(|entries| {
+                entries
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect()
+            })
+            .map_err(|err| format!("failed to read {}: {}", dir.display(), err))?;
+        paths.sort();
+
+        for path in paths {
+            let res = match path.extension().and_then(|ext| ext.to_str()) {
+                Some("key") => trust.add_key(&path),
+                Some("pem") => trust.add_cert(&path),
+                _ => continue,
+            };
+
+            if let Err(err) = res {
+                eprintln!("ignoring {}: {}", path.display(), err);
+            }
+        }
+
+        Ok(trust)
+    }
+
+    /// Trusts only `keys`, without certificates.
+    #[cfg(test)]
+    pub(crate) fn with_keys(keys: Vec<String>) -> Self {
+        Trust {
+            keys,
+            certs: Vec::new(),
+        }
+    }
+
+    fn add_key(&mut self, path: &Path) -> Result<(), String> {
+        let key = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let key = key.trim();
+        tail::decode_key(key)?;
+        self.keys.push(key.to_string());
+        Ok(())
+    }
+
+    fn add_cert(&mut self, path: &Path) -> Result<(), String> {
+        let cert = fs::read(path).map_err(|err| err.to_string())?;
+        reqwest::Certificate::from_pem(&cert).map_err(|err| err.to_string())?;
+        self.certs.push(cert);
+        Ok(())
+    }
+
+    /// Downloads the tail block of a project branch, trusting any of the certificates.
+    pub fn download_tail(&self, project: &str, branch: &str) -> Result<SignedTail, Failure> {
+        SignedTail::download(config::url(), project, branch, &self.certs)
+    }
+
+    /// Verifies the signature of `signed` against each of the keys until one matches.
+    pub fn verify(&self, signed: &SignedTail) -> Result<Tail, Failure> {
+        let mut errors = Vec::new();
+        for key in &self.keys {
+            match signed.verify(key) {
+                Ok(tail) => return Ok(tail),
+                Err(err) => errors.push(err),
+            }
+        }
+        Err(Failure::new(FailureKind::Verification, errors.join(", ")))
+    }
+
+    /// Returns the sources of objects: the local mirror, if configured, or else the server
+    /// trusting each of the certificates, to try in order.
+    pub fn sources(&self) -> Result<Vec<Source>, String> {
+        if let Some(dir) = download::local_mirror(config::url()) {
+            return Ok(vec![Source::Local(dir)]);
+        }
+
+        self.certs
+            .iter()
+            .map(|cert| {
+                let client = reqwest::blocking::Client::builder()
+                    .add_root_certificate(
+                        reqwest::Certificate::from_pem(cert).map_err(|err| err.to_string())?,
+                    )
+                    .build()
+                    .map_err(|err| err.to_string())?;
+                Ok(Source::Http {
+                    url: config::url().to_string(),
+                    client,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tail::tests::sign_digest;
+
+    #[test]
+    fn verify_any_key() {
+        let (first, first_signed) = sign_digest(1, 5, 1000, &[7; 48]);
+        let (second, second_signed) = sign_digest(2, 6, 1000, &[7; 48]);
+        let trust = Trust::with_keys(vec![first, second]);
+
+        assert_eq!(trust.verify(&first_signed).unwrap().counter, 5);
+        assert_eq!(trust.verify(&second_signed).unwrap().counter, 6);
+    }
+
+    #[test]
+    fn verify_no_key() {
+        let (first, _) = sign_digest(1, 5, 1000, &[7; 48]);
+        let (second, _) = sign_digest(2, 5, 1000, &[7; 48]);
+        let (_, signed) = sign_digest(3, 5, 1000, &[7; 48]);
+
+        let failure = Trust::with_keys(vec![first, second])
+            .verify(&signed)
+            .unwrap_err();
+        assert_eq!(failure.kind, FailureKind::Verification);
+        // One error for each key
+        assert_eq!(failure.message.split(", ").count(), 2);
+
+        let failure = Trust::with_keys(Vec::new()).verify(&signed).unwrap_err();
+        assert_eq!(failure.kind, FailureKind::Verification);
+    }
+}
@@ -1,9 +1,13 @@
 use lzma::reader::LzmaReader;
 use sha2::{Digest, Sha256};
-use std::io::Read;
-use std::{fs, io, path, process};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::time::Duration;
+use std::{fs, io, path, process, thread};
 use tar::Archive;
 
+use crate::download::{Failure, FailureKind};
+
 pub fn get_efi_mnt() -> Option<String> {
     let bootctl_esp = process::Command::new("bootctl")
         .args(["--print-esp-path"])
@@ -26,15 +30,15 @@ pub fn get_efi_mnt() -> Option<String> {
     })
 }
 
-pub fn extract<P: AsRef<path::Path>>(data: &[u8], p: P) -> io::Result<()> {
-    let decompressor = LzmaReader::new_decompressor(data)
-        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+/// Extracts an LZMA compressed tar archive read from `reader` into `p`.
+pub fn extract<R: Read, P: AsRef<path::Path>>(reader: R, p: P) -> io::Result<()> {
+    let decompressor = LzmaReader::new_decompressor(reader).map_err(io::Error::other)?;
     let mut tar = Archive::new(decompressor);
 
     for file_res in tar.entries()? {
         let mut file = file_res?;
 
-        println!("{:?}", file.path());
+        eprintln!("{:?}", file.path());
         if !file.unpack_in(&p)? {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -46,9 +50,9 @@ pub fn extract<P: AsRef<path::Path>>(data: &[u8], p: P) -> io::Result<()> {
     Ok(())
 }
 
-pub fn extract_file<P: AsRef<path::Path>>(data: &[u8], path: P) -> io::Result<String> {
-    let decompressor = LzmaReader::new_decompressor(data)
-        .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+/// Reads the file at `path` in an LZMA compressed tar archive read from `reader`.
+pub fn extract_file<R: Read, P: AsRef<path::Path>>(reader: R, path: P) -> io::Result<String> {
+    let decompressor = LzmaReader::new_decompressor(reader).map_err(io::Error::other)?;
     let mut tar = Archive::new(decompressor);
 
     for file_res in tar.entries()? {
@@ -60,7 +64,7 @@ pub fn extract_file<P: AsRef<path::Path>>(data: &[u8], path: P) -> io::Result<St
             }
         }
 
-        println!("{:?}", file.path());
+        eprintln!("{:?}", file.path());
         let mut s = String::new();
         file.read_to_string(&mut s)?;
         return Ok(s);
@@ -72,6 +76,28 @@ pub fn extract_file<P: AsRef<path::Path>>(data: &[u8], path: P) -> io::Result<St
     ))
 }
 
+/// Lists the files below `dir`, relative to it.
+pub fn list_files<P: AsRef<path::Path>>(dir: P) -> io::Result<Vec<path::PathBuf>> {
+    fn walk(root: &path::Path, dir: &path::Path, files: &mut Vec<path::PathBuf>) -> io::Result<()> {
+        for entry_res in fs::read_dir(dir)? {
+            let entry = entry_res?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir() {
+                walk(root, &path, files)?;
+            } else if let Ok(relative) = path.strip_prefix(root) {
+                files.push(relative.to_owned());
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut files = Vec::new();
+    walk(dir.as_ref(), dir.as_ref(), &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
 pub fn read_string<P: AsRef<path::Path>>(p: P) -> io::Result<String> {
     let mut string = String::new();
     {
@@ -81,28 +107,157 @@ pub fn read_string<P: AsRef<path::Path>>(p: P) -> io::Result<String> {
     Ok(string)
 }
 
+/// Writes `data` to a temporary file in the same directory as `path`, syncs it, and renames it
+/// into place, so that readers and crashes never see a partial file.
+pub fn write_atomic<P: AsRef<path::Path>>(path: P, data: &[u8], mode: u32) -> io::Result<()> {
+    let path = path.as_ref();
+    let parent = path.parent().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} has no parent directory", path.display()),
+        )
+    })?;
+
+    let mut file = tempfile::NamedTempFile::new_in(parent)?;
+    file.as_file()
+        .set_permissions(fs::Permissions::from_mode(mode))?;
+    file.write_all(data)?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|err| err.error)?;
+    sync_dir(parent)
+}
+
+/// Syncs a directory, so that files renamed into it are still there after a crash.
+pub fn sync_dir<P: AsRef<path::Path>>(path: P) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
 pub fn sha256(input: &[u8]) -> String {
     format!("{:x}", Sha256::digest(input))
 }
 
-pub fn retry<T, E>(
-    action: impl Fn() -> Result<T, E>,
-    cleanup: impl Fn() -> Result<(), E>,
-) -> Result<T, E> {
-    let mut tries = 0;
+/// How `RetryPolicy::retry` backs off between retries of transient failures.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Number of times a transient failure is retried
+    pub retries: u32,
+    /// Delay before the first retry, which doubles with each retry after it
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
 
-    loop {
-        let result = action();
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
 
-        if result.is_err() {
-            cleanup()?;
+impl RetryPolicy {
+    /// Runs `action` until it succeeds or fails in a way that retrying cannot fix, returning the
+    /// last failure so that callers can tell how it failed.
+    ///
+    /// - Transient failures are retried after an exponential backoff with jitter.
+    /// - A missing tail or object may mean the cached tail is out of date, so `cleanup` is called
+    ///   to discard it, and the action is retried once.
+    /// - Verification and other failures are returned at once.
+    pub fn retry<T>(
+        &self,
+        mut action: impl FnMut() -> Result<T, Failure>,
+        mut cleanup: impl FnMut() -> Result<(), String>,
+    ) -> Result<T, Failure> {
+        let mut retries = 0;
+        let mut cleaned = false;
 
-            if tries < 3 {
-                tries += 1;
-                continue;
+        loop {
+            let failure = match action() {
+                Ok(ok) => return Ok(ok),
+                Err(failure) => failure,
+            };
+
+            match failure.kind {
+                FailureKind::Transient if retries < self.retries => {
+                    let delay = self.delay(retries);
+                    eprintln!("{}, retrying in {:.1}s", failure, delay.as_secs_f64());
+                    thread::sleep(delay);
+                    retries += 1;
+                }
+                FailureKind::NotFound if !cleaned => {
+                    eprintln!("{}, retrying with a new tail", failure);
+                    cleanup()?;
+                    cleaned = true;
+                }
+                _ => return Err(failure),
+            }
+        }
+    }
+
+    /// Returns the delay before retry `n`, between half and all of the exponential backoff, so
+    /// that machines that failed together do not retry together.
+    fn delay(&self, n: u32) -> Duration {
+        let backoff = self
+            .base_delay
+            .saturating_mul(1 << n.min(16))
+            .min(self.max_delay);
+        let half = backoff / 2;
+        half + Duration::from_millis(fastrand::u64(..=half.as_millis() as u64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn delay_bounds() {
+        let policy = RetryPolicy::default();
+        for n in 0..40 {
+            let backoff = (policy.base_delay * 2u32.pow(n.min(16))).min(policy.max_delay);
+            for _ in 0..100 {
+                let delay = policy.delay(n);
+                assert!(delay >= backoff / 2, "retry {}: {:?}", n, delay);
+                assert!(delay <= backoff, "retry {}: {:?}", n, delay);
             }
         }
+    }
+
+    fn no_delay() -> RetryPolicy {
+        RetryPolicy {
+            retries: 4,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn retry_transient() {
+        let attempts = Cell::new(0);
+        let result = no_delay().retry(
+            || {
+                attempts.set(attempts.get() + 1);
+                Err::<(), _>(Failure::new(FailureKind::Transient, "timed out"))
+            },
+            || Ok(()),
+        );
+        assert_eq!(result.unwrap_err().kind, FailureKind::Transient);
+        assert_eq!(attempts.get(), 5);
+    }
 
-        return result;
+    #[test]
+    fn retry_verification() {
+        let attempts = Cell::new(0);
+        let result = no_delay().retry(
+            || {
+                attempts.set(attempts.get() + 1);
+                Err::<(), _>(Failure::new(FailureKind::Verification, "bad signature"))
+            },
+            || Ok(()),
+        );
+        assert_eq!(result.unwrap_err().kind, FailureKind::Verification);
+        assert_eq!(attempts.get(), 1);
     }
 }